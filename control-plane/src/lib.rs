@@ -0,0 +1,281 @@
+// 标签化 RISC-V 的 LLC 和内存控制器控制平面
+//
+// 两个控制平面都按标签（DS-id）分组，每个标签占 0x20 字节，寄存器都是 64 位：
+//   LLC：       0x00 路掩码      0x08 命中次数  0x10 缺失次数
+//   内存控制器：0x00 令牌桶大小  0x08 周期      0x10 每周期令牌数  0x18 访存流量（字节）
+// 寄存器布局和设备树绑定见 README 的“控制平面”一节。
+//
+// 这里只按给定的基地址读写寄存器，不关心地址从哪里来，可以在主机上对着一块内存测试，测试在 tests/ 中
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+/// 每个标签的寄存器占的字节数
+pub const LABEL_STRIDE: usize = 0x20;
+/// 最多支持的标签数
+pub const MAX_LABELS: usize = 16;
+
+pub mod llc_offsets {
+    pub const WAY_MASK: usize = 0x00;
+    pub const HITS: usize = 0x08;
+    pub const MISSES: usize = 0x10;
+}
+
+pub mod mem_offsets {
+    pub const SIZE: usize = 0x00;
+    pub const FREQ: usize = 0x08;
+    pub const INC: usize = 0x10;
+    pub const TRAFFIC: usize = 0x18;
+}
+
+/// 访存带宽令牌桶：每 `freq` 个周期加入 `inc` 个令牌，最多存 `size` 个
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenBucket {
+    pub size: usize,
+    pub freq: usize,
+    pub inc: usize,
+}
+
+/// 不限制带宽的令牌桶：每个周期补满
+pub const UNLIMITED_BUCKET: TokenBucket = TokenBucket {
+    size: u32::MAX as usize,
+    freq: 1,
+    inc: u32::MAX as usize,
+};
+
+/// 一个标签的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LabelStats {
+    pub llc_hits: u64,
+    pub llc_misses: u64,
+    /// 访存流量，字节
+    pub traffic: u64,
+}
+
+/// LLC 控制平面
+pub struct LlcControlPlane {
+    base: usize,
+    size: usize,
+    labels: usize,
+    ways: usize,
+}
+
+/// 内存控制器控制平面
+pub struct MemControlPlane {
+    base: usize,
+    size: usize,
+    labels: usize,
+}
+
+impl LlcControlPlane {
+    /// `[base, base + size)` 为寄存器所在的区域，标签数最多 [`MAX_LABELS`] 个，路数最多 64 路。
+    ///
+    /// # Safety
+    ///
+    /// `base` 开始的 `labels` 组寄存器必须可以按 64 位读写，并且没有别人同时访问
+    pub unsafe fn new(base: usize, size: usize, labels: usize, ways: usize) -> Self {
+        Self {
+            base,
+            size,
+            labels: labels.min(MAX_LABELS).min(size / LABEL_STRIDE),
+            ways: ways.min(usize::BITS as usize),
+        }
+    }
+
+    pub fn labels(&self) -> usize {
+        self.labels
+    }
+
+    pub fn ways(&self) -> usize {
+        self.ways
+    }
+
+    /// 包含全部路的掩码
+    pub fn all_ways(&self) -> usize {
+        low_bits(self.ways)
+    }
+
+    fn read(&self, dsid: usize, offset: usize) -> u64 {
+        unsafe { read_volatile((self.base + dsid * LABEL_STRIDE + offset) as *const u64) }
+    }
+
+    fn write(&self, dsid: usize, offset: usize, value: u64) {
+        unsafe { write_volatile((self.base + dsid * LABEL_STRIDE + offset) as *mut u64, value) }
+    }
+}
+
+impl MemControlPlane {
+    /// `[base, base + size)` 为寄存器所在的区域，标签数最多 [`MAX_LABELS`] 个。
+    ///
+    /// # Safety
+    ///
+    /// `base` 开始的 `labels` 组寄存器必须可以按 64 位读写，并且没有别人同时访问
+    pub unsafe fn new(base: usize, size: usize, labels: usize) -> Self {
+        Self {
+            base,
+            size,
+            labels: labels.min(MAX_LABELS).min(size / LABEL_STRIDE),
+        }
+    }
+
+    pub fn labels(&self) -> usize {
+        self.labels
+    }
+
+    fn read(&self, dsid: usize, offset: usize) -> u64 {
+        unsafe { read_volatile((self.base + dsid * LABEL_STRIDE + offset) as *const u64) }
+    }
+
+    fn write(&self, dsid: usize, offset: usize, value: u64) {
+        unsafe { write_volatile((self.base + dsid * LABEL_STRIDE + offset) as *mut u64, value) }
+    }
+}
+
+/// LLC 和内存控制器的控制平面，可以只有其中一个；
+/// 只有一个时，另一个的参数读出为零，设置时忽略。超出标签数的 DS-id 同样读出为零，设置时忽略
+pub struct ControlPlane {
+    pub llc: Option<LlcControlPlane>,
+    pub mem: Option<MemControlPlane>,
+}
+
+impl ControlPlane {
+    /// 两者的标签数中较大的一个，DS-id 从 0 开始
+    pub fn num_labels(&self) -> usize {
+        let llc = self.llc.as_ref().map_or(0, |llc| llc.labels);
+        let mem = self.mem.as_ref().map_or(0, |mem| mem.labels);
+        llc.max(mem)
+    }
+
+    /// LLC 的路数，没有 LLC 控制平面时为 0
+    pub fn cache_ways(&self) -> usize {
+        self.llc.as_ref().map_or(0, |llc| llc.ways)
+    }
+
+    pub fn way_mask(&self, dsid: usize) -> usize {
+        match self.llc.as_ref().filter(|llc| dsid < llc.labels) {
+            Some(llc) => llc.read(dsid, llc_offsets::WAY_MASK) as usize,
+            None => 0,
+        }
+    }
+
+    /// 超出 LLC 路数的位被忽略
+    pub fn set_way_mask(&mut self, dsid: usize, mask: usize) {
+        if let Some(llc) = self.llc.as_ref().filter(|llc| dsid < llc.labels) {
+            llc.write(dsid, llc_offsets::WAY_MASK, (mask & llc.all_ways()) as u64);
+        }
+    }
+
+    pub fn bucket(&self, dsid: usize) -> TokenBucket {
+        match self.mem.as_ref().filter(|mem| dsid < mem.labels) {
+            Some(mem) => TokenBucket {
+                size: mem.read(dsid, mem_offsets::SIZE) as usize,
+                freq: mem.read(dsid, mem_offsets::FREQ) as usize,
+                inc: mem.read(dsid, mem_offsets::INC) as usize,
+            },
+            None => TokenBucket {
+                size: 0,
+                freq: 0,
+                inc: 0,
+            },
+        }
+    }
+
+    /// `freq` 为 0 表示不限制带宽，写入 [`UNLIMITED_BUCKET`]
+    pub fn set_bucket(&mut self, dsid: usize, bucket: TokenBucket) {
+        if let Some(mem) = self.mem.as_ref().filter(|mem| dsid < mem.labels) {
+            let bucket = if bucket.freq == 0 { UNLIMITED_BUCKET } else { bucket };
+            mem.write(dsid, mem_offsets::SIZE, bucket.size as u64);
+            mem.write(dsid, mem_offsets::FREQ, bucket.freq as u64);
+            mem.write(dsid, mem_offsets::INC, bucket.inc as u64);
+        }
+    }
+
+    pub fn stats(&self, dsid: usize) -> LabelStats {
+        let mut stats = LabelStats::default();
+        if let Some(llc) = self.llc.as_ref().filter(|llc| dsid < llc.labels) {
+            stats.llc_hits = llc.read(dsid, llc_offsets::HITS);
+            stats.llc_misses = llc.read(dsid, llc_offsets::MISSES);
+        }
+        if let Some(mem) = self.mem.as_ref().filter(|mem| dsid < mem.labels) {
+            stats.traffic = mem.read(dsid, mem_offsets::TRAFFIC);
+        }
+        stats
+    }
+
+    /// 寄存器所在的区域 `(起始地址, 大小)`
+    pub fn mmio_regions(&self) -> Vec<(usize, usize)> {
+        let llc = self.llc.as_ref().map(|llc| (llc.base, llc.size));
+        let mem = self.mem.as_ref().map(|mem| (mem.base, mem.size));
+        llc.into_iter().chain(mem).collect()
+    }
+
+    /// 写入默认划分：`way_masks` 和 `buckets` 按标签排列，没有给出的标签共享全部路，不限制带宽
+    pub fn apply_defaults(&mut self, way_masks: &[usize], buckets: &[TokenBucket]) {
+        for dsid in 0..self.num_labels() {
+            let mask = way_masks
+                .get(dsid)
+                .map(|&mask| mask & low_bits(self.cache_ways()))
+                .filter(|&mask| mask != 0)
+                .unwrap_or(usize::MAX);
+            self.set_way_mask(dsid, mask);
+            self.set_bucket(dsid, buckets.get(dsid).copied().unwrap_or(UNLIMITED_BUCKET));
+        }
+    }
+
+    /// 按策略划分 LLC 和访存带宽，策略没有涉及的标签保持不变
+    pub fn apply_policy(&mut self, policy: &Policy) {
+        let labels = self.num_labels();
+        for (dsid, mask) in policy.way_masks(self.cache_ways()).into_iter().enumerate().take(labels) {
+            self.set_way_mask(dsid, mask);
+        }
+        for (dsid, &bucket) in policy.buckets.iter().enumerate().take(labels) {
+            self.set_bucket(dsid, bucket);
+        }
+    }
+}
+
+/// 一个划分策略
+pub struct Policy {
+    pub name: String,
+    /// 每个标签占 LLC 路数的百分比，按标签顺序从低位开始连续分配
+    pub way_shares: Vec<u32>,
+    /// 每个标签的令牌桶，freq 为 0 表示不限制
+    pub buckets: Vec<TokenBucket>,
+}
+
+impl Policy {
+    /// `ways` 路的 LLC 中每个标签的路掩码；份额不足一路的按一路算，路数用完后共用最高的一路
+    pub fn way_masks(&self, ways: usize) -> Vec<usize> {
+        if ways == 0 {
+            return Vec::new();
+        }
+        let total: u32 = self.way_shares.iter().sum();
+        let mut next = 0;
+        let mut masks = Vec::with_capacity(self.way_shares.len());
+        for (index, &share) in self.way_shares.iter().enumerate() {
+            let remaining = ways.saturating_sub(next);
+            let count = if index + 1 == self.way_shares.len() && total == 100 {
+                // 份额加起来是 100% 时，最后一个标签拿走剩下的路
+                remaining
+            } else {
+                ((share as usize * ways + 50) / 100).max(1).min(remaining)
+            };
+            let mask = if count == 0 {
+                1 << (ways - 1)
+            } else {
+                low_bits(count) << next
+            };
+            masks.push(mask);
+            next += count;
+        }
+        masks
+    }
+}
+
+fn low_bits(count: usize) -> usize {
+    1usize.checked_shl(count as u32).map_or(usize::MAX, |bit| bit - 1)
+}
@@ -0,0 +1,189 @@
+// 把一块内存当作控制平面的寄存器，检查默认划分和策略写进了哪些寄存器
+use control_plane::{
+    llc_offsets, mem_offsets, ControlPlane, LabelStats, LlcControlPlane, MemControlPlane, Policy, TokenBucket,
+    LABEL_STRIDE, UNLIMITED_BUCKET,
+};
+
+const WORDS_PER_LABEL: usize = LABEL_STRIDE / 8;
+
+// 模拟的寄存器，每个标签 4 个 64 位寄存器
+struct Registers(Vec<u64>);
+
+impl Registers {
+    fn new(labels: usize) -> Self {
+        Self(vec![0; labels * WORDS_PER_LABEL])
+    }
+
+    fn base(&mut self) -> usize {
+        self.0.as_mut_ptr() as usize
+    }
+
+    fn size(&self) -> usize {
+        self.0.len() * 8
+    }
+
+    fn get(&self, dsid: usize, offset: usize) -> u64 {
+        self.0[dsid * WORDS_PER_LABEL + offset / 8]
+    }
+
+    fn set(&mut self, dsid: usize, offset: usize, value: u64) {
+        self.0[dsid * WORDS_PER_LABEL + offset / 8] = value;
+    }
+
+    fn bucket(&self, dsid: usize) -> TokenBucket {
+        TokenBucket {
+            size: self.get(dsid, mem_offsets::SIZE) as usize,
+            freq: self.get(dsid, mem_offsets::FREQ) as usize,
+            inc: self.get(dsid, mem_offsets::INC) as usize,
+        }
+    }
+}
+
+fn control_plane(llc: &mut Registers, mem: &mut Registers, labels: usize, ways: usize) -> ControlPlane {
+    unsafe {
+        ControlPlane {
+            llc: Some(LlcControlPlane::new(llc.base(), llc.size(), labels, ways)),
+            mem: Some(MemControlPlane::new(mem.base(), mem.size(), labels)),
+        }
+    }
+}
+
+fn policy(way_shares: &[u32], buckets: &[TokenBucket]) -> Policy {
+    Policy {
+        name: String::from("test"),
+        way_shares: way_shares.to_vec(),
+        buckets: buckets.to_vec(),
+    }
+}
+
+const LIMITED: TokenBucket = TokenBucket {
+    size: 8,
+    freq: 100,
+    inc: 8,
+};
+
+const NO_LIMIT: TokenBucket = TokenBucket {
+    size: 0,
+    freq: 0,
+    inc: 0,
+};
+
+#[test]
+fn policy_reaches_registers() {
+    let mut llc = Registers::new(2);
+    let mut mem = Registers::new(2);
+    let mut cp = control_plane(&mut llc, &mut mem, 2, 16);
+    cp.apply_policy(&policy(&[75, 25], &[NO_LIMIT, LIMITED]));
+    assert_eq!(llc.get(0, llc_offsets::WAY_MASK), 0x0fff);
+    assert_eq!(llc.get(1, llc_offsets::WAY_MASK), 0xf000);
+    // freq 为 0 写成不限制带宽的令牌桶
+    assert_eq!(mem.bucket(0), UNLIMITED_BUCKET);
+    assert_eq!(mem.bucket(1), LIMITED);
+    // 统计寄存器没有被写
+    assert_eq!(llc.get(0, llc_offsets::HITS), 0);
+    assert_eq!(mem.get(1, mem_offsets::TRAFFIC), 0);
+}
+
+#[test]
+fn policy_leaves_other_labels_alone() {
+    let mut llc = Registers::new(4);
+    let mut mem = Registers::new(4);
+    let mut cp = control_plane(&mut llc, &mut mem, 4, 8);
+    cp.apply_defaults(&[], &[]);
+    cp.apply_policy(&policy(&[50, 25], &[LIMITED]));
+    assert_eq!(llc.get(0, llc_offsets::WAY_MASK), 0x0f);
+    assert_eq!(llc.get(1, llc_offsets::WAY_MASK), 0x30);
+    assert_eq!(llc.get(2, llc_offsets::WAY_MASK), 0xff);
+    assert_eq!(llc.get(3, llc_offsets::WAY_MASK), 0xff);
+    assert_eq!(mem.bucket(0), LIMITED);
+    assert_eq!(mem.bucket(1), UNLIMITED_BUCKET);
+}
+
+#[test]
+fn policy_beyond_labels_is_dropped() {
+    let mut llc = Registers::new(2);
+    let mut mem = Registers::new(2);
+    // 第三个标签的寄存器不存在，写它会越过模拟的寄存器
+    let mut cp = control_plane(&mut llc, &mut mem, 2, 16);
+    cp.apply_policy(&policy(&[50, 25, 25], &[LIMITED, LIMITED, LIMITED]));
+    assert_eq!(llc.get(0, llc_offsets::WAY_MASK), 0x00ff);
+    assert_eq!(llc.get(1, llc_offsets::WAY_MASK), 0x0f00);
+    assert_eq!(mem.bucket(1), LIMITED);
+}
+
+#[test]
+fn labels_are_limited_by_register_window() {
+    let mut llc = Registers::new(2);
+    let mut mem = Registers::new(2);
+    // 设备树声称有 4 个标签，但寄存器区域只够 2 个
+    let cp = control_plane(&mut llc, &mut mem, 4, 16);
+    assert_eq!(cp.num_labels(), 2);
+}
+
+#[test]
+fn defaults_fill_missing_labels() {
+    let mut llc = Registers::new(3);
+    let mut mem = Registers::new(3);
+    let mut cp = control_plane(&mut llc, &mut mem, 3, 16);
+    // 0 号标签的掩码为 0 ，当作没有给出；超出路数的位被去掉
+    cp.apply_defaults(&[0, 0x1_00ff], &[LIMITED]);
+    assert_eq!(llc.get(0, llc_offsets::WAY_MASK), 0xffff);
+    assert_eq!(llc.get(1, llc_offsets::WAY_MASK), 0x00ff);
+    assert_eq!(llc.get(2, llc_offsets::WAY_MASK), 0xffff);
+    assert_eq!(mem.bucket(0), LIMITED);
+    assert_eq!(mem.bucket(1), UNLIMITED_BUCKET);
+    assert_eq!(mem.bucket(2), UNLIMITED_BUCKET);
+}
+
+#[test]
+fn llc_only() {
+    let mut llc = Registers::new(2);
+    let mut cp = unsafe {
+        ControlPlane {
+            llc: Some(LlcControlPlane::new(llc.base(), llc.size(), 2, 4)),
+            mem: None,
+        }
+    };
+    cp.apply_policy(&policy(&[50, 50], &[LIMITED]));
+    assert_eq!(llc.get(0, llc_offsets::WAY_MASK), 0b0011);
+    assert_eq!(llc.get(1, llc_offsets::WAY_MASK), 0b1100);
+    assert_eq!(cp.bucket(0).freq, 0);
+    assert_eq!(cp.mmio_regions(), vec![(llc.base(), llc.size())]);
+}
+
+#[test]
+fn reads_registers() {
+    let mut llc = Registers::new(2);
+    let mut mem = Registers::new(2);
+    llc.set(1, llc_offsets::WAY_MASK, 0xf0);
+    llc.set(1, llc_offsets::HITS, 100);
+    llc.set(1, llc_offsets::MISSES, 7);
+    mem.set(1, mem_offsets::TRAFFIC, 4096);
+    mem.set(1, mem_offsets::FREQ, 10);
+    let cp = control_plane(&mut llc, &mut mem, 2, 8);
+    assert_eq!(cp.way_mask(1), 0xf0);
+    assert_eq!(cp.bucket(1).freq, 10);
+    assert_eq!(
+        cp.stats(1),
+        LabelStats {
+            llc_hits: 100,
+            llc_misses: 7,
+            traffic: 4096,
+        }
+    );
+    // 超出标签数的读出为零
+    assert_eq!(cp.way_mask(2), 0);
+    assert_eq!(cp.stats(2), LabelStats::default());
+}
+
+#[test]
+fn way_masks() {
+    assert_eq!(policy(&[50, 50], &[]).way_masks(16), vec![0x00ff, 0xff00]);
+    assert_eq!(policy(&[75, 25], &[]).way_masks(16), vec![0x0fff, 0xf000]);
+    // 份额加起来是 100% 时最后一个标签拿走剩下的路
+    assert_eq!(policy(&[33, 33, 34], &[]).way_masks(4), vec![0b0001, 0b0010, 0b1100]);
+    // 份额不足一路的按一路算，路数用完后共用最高的一路
+    assert_eq!(policy(&[90, 5, 5], &[]).way_masks(4), vec![0b1111, 0b1000, 0b1000]);
+    assert_eq!(policy(&[100], &[]).way_masks(64), vec![usize::MAX]);
+    assert!(policy(&[50, 50], &[]).way_masks(0).is_empty());
+}
@@ -0,0 +1,151 @@
+// SBI 调用往返开销的基准测试，用 `ecall_bench` 特性打开
+//
+// hart 0 在进入内核之前从 S 态反复发起同一个调用，用 rdcycle 测量 ecall 到返回的周期数，
+// 每个调用分别在关闭和打开快速路径时测一遍。测量循环在固件代码中，测试期间临时让 S 态
+// 可以访问全部地址，之后由 init_pmp 重新配置
+use core::arch::global_asm;
+
+use riscv::register::{mcounteren, mhartid, mie, mip};
+use rustsbi::println;
+
+use crate::{hal, hart, pmp};
+
+const ITERATIONS: usize = 1000;
+
+// S 态的测量循环：a0 为次数，a1 、a2 为 EID 、FID，a3 、a4 为参数；
+// 结束时用 EID 0x08004245（和 trap.S 中的 EID_BENCH_EXIT 一致）的调用返回总周期数和最小周期数
+global_asm!(
+    "
+    .section .text
+    .global _ecall_bench_loop
+_ecall_bench_loop:
+    mv      s0, a0
+    mv      s1, a1
+    mv      s2, a2
+    mv      s3, a3
+    mv      s4, a4
+    li      s5, 0
+    li      s6, -1
+1:
+    beqz    s0, 3f
+    mv      a0, s3
+    mv      a1, s4
+    mv      a6, s2
+    mv      a7, s1
+    rdcycle s7
+    ecall
+    rdcycle s8
+    sub     s8, s8, s7
+    add     s5, s5, s8
+    bgeu    s8, s6, 2f
+    mv      s6, s8
+2:
+    addi    s0, s0, -1
+    j       1b
+3:
+    mv      a0, s5
+    mv      a1, s6
+    li      a7, 0x08004245
+    ecall
+"
+);
+
+#[repr(C)]
+struct Cycles {
+    total: u64,
+    min: u64,
+}
+
+extern "C" {
+    static _ecall_bench_loop: u8;
+    fn _ecall_bench_enter(entry: usize, iterations: usize, eid: usize, fid: usize, arg0: usize, arg1: usize) -> Cycles;
+}
+
+struct Case {
+    name: &'static str,
+    eid: usize,
+    fid: usize,
+    args: [usize; 2],
+    // 没有快速路径的调用只测一遍，作为完整处理的对照
+    fast_path: bool,
+}
+
+const CASES: [Case; 3] = [
+    Case {
+        name: "set_timer",
+        eid: 0x5449_4D45,
+        fid: 0,
+        args: [u64::MAX as usize, 0],
+        fast_path: true,
+    },
+    // 目标为空，只测调用本身
+    Case {
+        name: "send_ipi",
+        eid: 0x73_5049,
+        fid: 0,
+        args: [0, 0],
+        fast_path: true,
+    },
+    Case {
+        name: "get_spec_version",
+        eid: 0x10,
+        fid: 0,
+        args: [0, 0],
+        fast_path: false,
+    },
+];
+
+/// 在 hart 0 进入内核之前、init_pmp 之前调用，结果打印到控制台
+pub fn run() {
+    let info = pmp::probe();
+    if info.entries != 0 {
+        let mut manager = pmp::PmpManager::new(info);
+        let _ = manager.set_default(pmp::cfg::R | pmp::cfg::W | pmp::cfg::X);
+        manager.apply();
+    }
+    // 测量循环在 S 态按物理地址运行，软复位之前的程序可能留下了分页
+    crate::flush_translation();
+    unsafe { mcounteren::set_cy() };
+    let context = hart::current();
+    println!(
+        "[rustsbi-bench] SBI call round trip over {} iterations, cycles (average / minimum):",
+        ITERATIONS
+    );
+    for case in CASES.iter() {
+        context.set_fast_ecall(false);
+        let full = measure(case);
+        if case.fast_path {
+            context.set_fast_ecall(true);
+            let fast = measure(case);
+            println!(
+                "[rustsbi-bench] {:<16} full path {} / {}, fast path {} / {}",
+                case.name,
+                full.total / ITERATIONS as u64,
+                full.min,
+                fast.total / ITERATIONS as u64,
+                fast.min
+            );
+        } else {
+            println!(
+                "[rustsbi-bench] {:<16} full path {} / {}",
+                case.name,
+                full.total / ITERATIONS as u64,
+                full.min
+            );
+        }
+    }
+    context.set_fast_ecall(true);
+    // set_timer 留下的 mtimecmp 和 MTIE 恢复成启动时的样子
+    hal::clint().set_mtimecmp(mhartid::read(), u64::MAX);
+    unsafe {
+        mie::clear_mtimer();
+        mip::clear_stimer();
+    }
+}
+
+fn measure(case: &Case) -> Cycles {
+    unsafe {
+        let entry = &_ecall_bench_loop as *const _ as usize;
+        _ecall_bench_enter(entry, ITERATIONS, case.eid, case.fid, case.args[0], case.args[1])
+    }
+}
@@ -0,0 +1,241 @@
+// 设备树的读取和查找
+use alloc::vec::Vec;
+use device_tree::{DeviceTree, Node};
+
+const DEVICE_TREE_MAGIC: u32 = 0xD00DFEED;
+
+#[repr(C)]
+struct DtbHeader {
+    magic: u32,
+    size: u32,
+}
+
+/// 解析 `dtb_pa` 处的设备树，魔数不对或者解析失败时返回 None
+pub unsafe fn load(dtb_pa: usize) -> Option<DeviceTree> {
+    let header = &*(dtb_pa as *const DtbHeader);
+    // from_be 是大小端序的转换（from big endian）
+    let magic = u32::from_be(header.magic);
+    if magic != DEVICE_TREE_MAGIC {
+        return None;
+    }
+    let size = u32::from_be(header.size);
+    // 拷贝数据，加载并遍历
+    let data = core::slice::from_raw_parts(dtb_pa as *const u8, size as usize);
+    DeviceTree::load(data).ok()
+}
+
+/// 设备树中的一个设备节点，带着父节点的 `#address-cells` 和 `#size-cells`，用于解析 `reg`
+pub struct Device<'a> {
+    pub node: &'a Node,
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Device<'a> {
+    /// `reg` 中的第 `index` 个区域，返回（起始地址，长度）
+    pub fn reg(&self, index: usize) -> Option<(usize, usize)> {
+        let reg = self.node.prop_raw("reg")?;
+        let entry_cells = self.address_cells + self.size_cells;
+        let start = index * entry_cells * 4;
+        let entry = reg.get(start..start + entry_cells * 4)?;
+        let (address, size) = entry.split_at(self.address_cells * 4);
+        Some((read_cells(address), read_cells(size)))
+    }
+
+    /// `reg-names` 中名为 `name` 的区域，没有 `reg-names` 或者其中没有这个名字时返回 None
+    pub fn reg_by_name(&self, name: &str) -> Option<(usize, usize)> {
+        let names = self.node.prop_raw("reg-names")?;
+        let index = names
+            .split(|&b| b == 0)
+            .position(|s| s == name.as_bytes())?;
+        self.reg(index)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.node.prop_u32(name).ok()
+    }
+
+    /// 由若干个 u32 组成的属性，没有时为空
+    pub fn prop_u32s(&self, name: &str) -> impl Iterator<Item = u32> + 'a {
+        self.node
+            .prop_raw(name)
+            .map(|value| value.as_slice())
+            .unwrap_or(&[])
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        is_compatible(self.node, compatible)
+    }
+}
+
+/// 节点的 `compatible` 字符串列表中是否包含 `compatible`
+pub fn is_compatible(node: &Node, compatible: &str) -> bool {
+    match node.prop_raw("compatible") {
+        Some(value) => value
+            .split(|&b| b == 0)
+            .any(|s| s == compatible.as_bytes()),
+        None => false,
+    }
+}
+
+/// 深度优先查找第一个与 `compatible` 中任意一项兼容、且没有被禁用的节点
+pub fn find_compatible<'a>(dt: &'a DeviceTree, compatible: &[&str]) -> Option<Device<'a>> {
+    let mut found = None;
+    walk(&dt.root, 2, 1, &mut |device| {
+        if found.is_none()
+            && is_enabled(device.node)
+            && compatible.iter().any(|c| device.is_compatible(c))
+        {
+            found = Some(device);
+        }
+    });
+    found
+}
+
+/// 深度优先查找所有与 `compatible` 中任意一项兼容、且没有被禁用的节点
+pub fn find_all_compatible<'a>(dt: &'a DeviceTree, compatible: &[&str]) -> Vec<Device<'a>> {
+    let mut found = Vec::new();
+    walk(&dt.root, 2, 1, &mut |device| {
+        if is_enabled(device.node) && compatible.iter().any(|c| device.is_compatible(c)) {
+            found.push(device);
+        }
+    });
+    found
+}
+
+/// 按绝对路径（如 `/soc/serial@60000000`）或 `/aliases` 中的别名查找节点
+pub fn find_path<'a>(dt: &'a DeviceTree, path: &str) -> Option<Device<'a>> {
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        let aliases = dt.find("/aliases")?;
+        aliases.prop_str(path).ok()?
+    };
+    let mut device = Device {
+        node: &dt.root,
+        address_cells: 2,
+        size_cells: 1,
+    };
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let (address_cells, size_cells) = child_cells(device.node);
+        let node = device.node.children.iter().find(|child| {
+            // 路径中可以省略单元地址
+            child.name == name || child.name.split('@').next() == Some(name)
+        })?;
+        device = Device {
+            node,
+            address_cells,
+            size_cells,
+        };
+    }
+    Some(device)
+}
+
+/// 是否所有 CPU 节点的 `riscv,isa` 或 `riscv,isa-extensions` 中都有多字母扩展 `extension`
+pub fn cpus_have_extension(dt: &DeviceTree, extension: &str) -> bool {
+    let cpus = match dt.find("/cpus") {
+        Some(cpus) => cpus,
+        None => return false,
+    };
+    let mut found = false;
+    for cpu in cpus.children.iter() {
+        if cpu.prop_str("device_type").ok() != Some("cpu") || !is_enabled(cpu) {
+            continue;
+        }
+        // 如 "rv64imac_zicsr_sstc"，多字母扩展用下划线分隔
+        let in_isa = match cpu.prop_str("riscv,isa") {
+            Ok(isa) => isa.split('_').skip(1).any(|e| e.eq_ignore_ascii_case(extension)),
+            Err(_) => false,
+        };
+        let in_list = match cpu.prop_raw("riscv,isa-extensions") {
+            Some(list) => list
+                .split(|&b| b == 0)
+                .any(|e| e.eq_ignore_ascii_case(extension.as_bytes())),
+            None => false,
+        };
+        if !in_isa && !in_list {
+            return false;
+        }
+        found = true;
+    }
+    found
+}
+
+/// `interrupts-extended` 中每一项对应的（hartid，中断原因），按 phandle 找到 CPU 的中断控制器；
+/// 找不到对应 CPU 的项 hartid 为 None
+pub fn hart_interrupts(dt: &DeviceTree, device: &Device) -> Vec<(Option<usize>, u32)> {
+    // （中断控制器的 phandle，hartid）
+    let mut intcs = Vec::new();
+    if let Some(cpus) = dt.find("/cpus") {
+        for cpu in cpus.children.iter() {
+            let hartid = match cpu.prop_u32("reg") {
+                Ok(hartid) => hartid as usize,
+                Err(_) => continue,
+            };
+            for intc in cpu.children.iter().filter(|child| is_compatible(child, "riscv,cpu-intc")) {
+                if let Ok(phandle) = intc.prop_u32("phandle").or_else(|_| intc.prop_u32("linux,phandle")) {
+                    intcs.push((phandle, hartid));
+                }
+            }
+        }
+    }
+    let cells: Vec<u32> = device.prop_u32s("interrupts-extended").collect();
+    cells
+        .chunks_exact(2)
+        .map(|cell| {
+            let hartid = intcs.iter().find(|&&(phandle, _)| phandle == cell[0]).map(|&(_, hartid)| hartid);
+            (hartid, cell[1])
+        })
+        .collect()
+}
+
+/// 第一个 `device_type` 为 `memory` 的节点的第一个区域
+#[cfg_attr(not(feature = "smepmp"), allow(dead_code))]
+pub fn memory(dt: &DeviceTree) -> Option<(usize, usize)> {
+    let mut found = None;
+    walk(&dt.root, 2, 1, &mut |device| {
+        if found.is_none() && device.node.prop_str("device_type").ok() == Some("memory") {
+            found = device.reg(0);
+        }
+    });
+    found
+}
+
+fn walk<'a>(
+    node: &'a Node,
+    address_cells: usize,
+    size_cells: usize,
+    f: &mut impl FnMut(Device<'a>),
+) {
+    f(Device {
+        node,
+        address_cells,
+        size_cells,
+    });
+    let (address_cells, size_cells) = child_cells(node);
+    for child in node.children.iter() {
+        walk(child, address_cells, size_cells, f);
+    }
+}
+
+// 节点的 `#address-cells` 和 `#size-cells` 作用于它的子节点
+fn child_cells(node: &Node) -> (usize, usize) {
+    let address_cells = node.prop_u32("#address-cells").unwrap_or(2) as usize;
+    let size_cells = node.prop_u32("#size-cells").unwrap_or(1) as usize;
+    (address_cells, size_cells)
+}
+
+fn is_enabled(node: &Node) -> bool {
+    match node.prop_str("status") {
+        Ok(status) => status == "okay" || status == "ok",
+        Err(_) => true,
+    }
+}
+
+fn read_cells(cells: &[u8]) -> usize {
+    cells
+        .chunks(4)
+        .fold(0, |acc, cell| (acc << 32) | u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]) as usize)
+}
@@ -0,0 +1,48 @@
+// rustsbi 0.2.1 还不支持的 SBI 扩展，在交给 rustsbi 之前先在这里处理
+use rustsbi::SbiRet;
+
+mod dbcn;
+pub mod lrv;
+pub mod pmu;
+mod susp;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 3;
+
+/// 处理固件自己实现的扩展；不认识的调用返回 None，交给 `rustsbi::ecall`
+pub fn handle(extension: usize, function: usize, param: [usize; 6]) -> Option<SbiRet> {
+    pmu::record(pmu::FirmwareEvent::SbiCall);
+    crate::hart::current().stats().record_sbi(extension, function);
+    match extension {
+        // rustsbi 不知道这些扩展，探测时要由这里回答
+        EXTENSION_BASE if function == FUNCTION_BASE_PROBE_EXTENSION && probe(param[0]) => {
+            Some(SbiRet::ok(1))
+        }
+        dbcn::EXTENSION_ID => Some(dbcn::handle(function, param)),
+        pmu::EXTENSION_ID => Some(pmu::handle(function, param)),
+        susp::EXTENSION_ID => Some(susp::handle(function, param)),
+        lrv::EXTENSION_ID => Some(lrv::handle(function, param)),
+        _ => None,
+    }
+}
+
+fn probe(extension: usize) -> bool {
+    match extension {
+        dbcn::EXTENSION_ID => dbcn::probe(),
+        pmu::EXTENSION_ID | susp::EXTENSION_ID | lrv::EXTENSION_ID => true,
+        _ => false,
+    }
+}
+
+#[allow(dead_code)]
+pub mod sbi_ret_value {
+    pub const SBI_SUCCESS: usize = 0;
+    pub const SBI_ERR_FAILED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-1));
+    pub const SBI_ERR_NOT_SUPPORTED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-2));
+    pub const SBI_ERR_INVALID_PARAM: usize = usize::from_ne_bytes(isize::to_ne_bytes(-3));
+    pub const SBI_ERR_DENIED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-4));
+    pub const SBI_ERR_INVALID_ADDRESS: usize = usize::from_ne_bytes(isize::to_ne_bytes(-5));
+    pub const SBI_ERR_ALREADY_AVAILABLE: usize = usize::from_ne_bytes(isize::to_ne_bytes(-6));
+    pub const SBI_ERR_ALREADY_STARTED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-7));
+    pub const SBI_ERR_ALREADY_STOPPED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-8));
+}
@@ -0,0 +1,97 @@
+// Debug Console 扩展（DBCN）
+//
+// 缓冲区由 S 态按物理地址给出，固件访问前先按 PMP 检查 S 态是否有权限；
+// 启用 Smepmp 时 M 态不能直接访问 S 态内存，访问期间通过 `pmp::open_window` 临时打开
+use embedded_hal::serial::{Read, Write};
+use rustsbi::SbiRet;
+
+use super::sbi_ret_value::*;
+use crate::hal::{self, Serial};
+use crate::pmp;
+
+pub const EXTENSION_ID: usize = 0x4442434E;
+
+const FUNCTION_CONSOLE_WRITE: usize = 0;
+const FUNCTION_CONSOLE_READ: usize = 1;
+const FUNCTION_CONSOLE_WRITE_BYTE: usize = 2;
+
+pub fn probe() -> bool {
+    hal::console().is_some()
+}
+
+pub fn handle(function: usize, param: [usize; 6]) -> SbiRet {
+    let mut console = hal::console();
+    let serial = match console.as_mut() {
+        Some(serial) => serial,
+        None => return error(SBI_ERR_NOT_SUPPORTED),
+    };
+    match function {
+        FUNCTION_CONSOLE_WRITE => console_write(serial, param[0], param[1], param[2]),
+        FUNCTION_CONSOLE_READ => console_read(serial, param[0], param[1], param[2]),
+        FUNCTION_CONSOLE_WRITE_BYTE => {
+            // 这个调用要求阻塞到字节写出为止
+            let _ = nb::block!(serial.write(param[0] as u8));
+            SbiRet::ok(0)
+        }
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+// 尽量写出缓冲区中的字节，发送缓冲区满时提前返回，由 S 态继续调用
+fn console_write(serial: &mut Serial, num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    let buf = match supervisor_buffer(num_bytes, base_lo, base_hi, false) {
+        Ok(buf) => buf,
+        Err(ret) => return ret,
+    };
+    let _window = pmp::open_window(base_lo, num_bytes);
+    let mut written = 0;
+    for &byte in buf.iter() {
+        match serial.write(byte) {
+            Ok(()) => written += 1,
+            Err(_) => break,
+        }
+    }
+    SbiRet::ok(written)
+}
+
+// 不等待，只读出已经收到的字节；收到的数据出错时丢弃
+fn console_read(serial: &mut Serial, num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    let buf = match supervisor_buffer(num_bytes, base_lo, base_hi, true) {
+        Ok(buf) => buf,
+        Err(ret) => return ret,
+    };
+    let _window = pmp::open_window(base_lo, num_bytes);
+    let mut read = 0;
+    while read < buf.len() {
+        match serial.read() {
+            Ok(byte) => {
+                buf[read] = byte;
+                read += 1;
+            }
+            Err(nb::Error::Other(_)) => continue,
+            Err(nb::Error::WouldBlock) => break,
+        }
+    }
+    SbiRet::ok(read)
+}
+
+// 检查 S 态给出的缓冲区，`write` 表示固件要写这块内存
+fn supervisor_buffer(
+    num_bytes: usize,
+    base_lo: usize,
+    base_hi: usize,
+    write: bool,
+) -> Result<&'static mut [u8], SbiRet> {
+    // RV64 的物理地址不超过 56 位，高半部分必须为零
+    if base_hi != 0 {
+        return Err(error(SBI_ERR_INVALID_PARAM));
+    }
+    if !pmp::supervisor_can_access(base_lo, num_bytes, write) {
+        return Err(error(SBI_ERR_INVALID_PARAM));
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(base_lo as *mut u8, num_bytes) })
+}
+
+fn error(error: usize) -> SbiRet {
+    SbiRet { error, value: 0 }
+}
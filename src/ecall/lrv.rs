@@ -0,0 +1,236 @@
+// 标签化 RISC-V 的厂商扩展
+//
+// S 态通过它设置当前硬件线程的 DS-id（标签），以及每个标签的 LLC 路分配和访存带宽令牌桶。
+// 控制平面寄存器只在 M 态访问，参数在这里检查后交给启动时 `init_control_plane` 注册的驱动；
+// 设备树中没有控制平面时，控制平面相关的函数返回 Not Supported。
+// 另外提供各硬件线程陷入和 SBI 调用统计的读取、清零和打印
+use alloc::vec::Vec;
+use control_plane::{ControlPlane, Policy, TokenBucket};
+use rustsbi::{println, SbiRet};
+use spin::Mutex;
+
+use super::sbi_ret_value::*;
+use crate::hart;
+use crate::stats;
+
+pub const EXTENSION_ID: usize = 0x0900_4C56;
+
+const FUNCTION_GET_DSID: usize = 0;
+const FUNCTION_SET_DSID: usize = 1;
+const FUNCTION_NUM_LABELS: usize = 2;
+const FUNCTION_GET_WAY_MASK: usize = 3;
+const FUNCTION_SET_WAY_MASK: usize = 4;
+const FUNCTION_GET_BUCKET: usize = 5;
+const FUNCTION_SET_BUCKET: usize = 6;
+const FUNCTION_GET_STAT: usize = 7;
+const FUNCTION_GET_TRAP_COUNT: usize = 8;
+const FUNCTION_GET_SBI_COUNT: usize = 9;
+const FUNCTION_GET_EMULATED_COUNT: usize = 10;
+const FUNCTION_GET_M_CYCLES: usize = 11;
+const FUNCTION_RESET_STATS: usize = 12;
+const FUNCTION_DUMP_STATS: usize = 13;
+
+// GET_BUCKET 的第二个参数
+const BUCKET_SIZE: usize = 0;
+const BUCKET_FREQ: usize = 1;
+const BUCKET_INC: usize = 2;
+
+// GET_STAT 的第二个参数
+const STAT_LLC_HITS: usize = 0;
+const STAT_LLC_MISSES: usize = 1;
+const STAT_TRAFFIC: usize = 2;
+
+static CONTROL_PLANE: Mutex<Option<ControlPlane>> = Mutex::new(None);
+
+/// 注册启动时从设备树中找到的控制平面
+pub fn init_control_plane(control_plane: ControlPlane) {
+    *CONTROL_PLANE.lock() = Some(control_plane);
+}
+
+/// 已注册的控制平面寄存器所在的地址范围，没有注册时为空
+pub fn control_plane_regions() -> Vec<(usize, usize)> {
+    match CONTROL_PLANE.lock().as_ref() {
+        Some(cp) => cp.mmio_regions(),
+        None => Vec::new(),
+    }
+}
+
+/// 在内核运行之前按策略划分 LLC 和访存带宽，并打印每个标签最终的路掩码和令牌桶；
+/// 策略没有涉及的标签保持不变。没有注册控制平面时返回 false
+pub fn apply_policy(policy: &Policy) -> bool {
+    let mut control_plane = CONTROL_PLANE.lock();
+    let cp = match control_plane.as_mut() {
+        Some(cp) => cp,
+        None => return false,
+    };
+    cp.apply_policy(policy);
+    for dsid in 0..cp.num_labels() {
+        let bucket = cp.bucket(dsid);
+        println!(
+            "[rustsbi] label {}: LLC ways {:#x}, bucket size {} freq {} inc {}",
+            dsid,
+            cp.way_mask(dsid),
+            bucket.size,
+            bucket.freq,
+            bucket.inc
+        );
+    }
+    true
+}
+
+pub fn handle(function: usize, param: [usize; 6]) -> SbiRet {
+    let result = match function {
+        FUNCTION_GET_DSID => dsid::read(),
+        FUNCTION_SET_DSID => set_dsid(param[0]),
+        FUNCTION_GET_TRAP_COUNT..=FUNCTION_DUMP_STATS => stats_call(function, param),
+        _ => with_control_plane(|cp| control_plane_call(cp, function, param)),
+    };
+    match result {
+        Ok(value) => SbiRet::ok(value),
+        Err(e) => SbiRet { error: e, value: 0 },
+    }
+}
+
+fn set_dsid(value: usize) -> Result<usize, usize> {
+    // 有控制平面时标签数以它为准，否则只能靠读回检查 CSR 的宽度
+    if let Ok(num_labels) = with_control_plane(|cp| Ok(cp.num_labels())) {
+        if value >= num_labels {
+            return Err(SBI_ERR_INVALID_PARAM);
+        }
+    }
+    let old = dsid::read()?;
+    dsid::write(value);
+    if dsid::read()? != value {
+        dsid::write(old);
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    Ok(0)
+}
+
+fn stats_call(function: usize, param: [usize; 6]) -> Result<usize, usize> {
+    match function {
+        FUNCTION_DUMP_STATS => {
+            stats::dump();
+            return Ok(0);
+        }
+        // hartid 为 -1 时清零所有硬件线程的统计
+        FUNCTION_RESET_STATS if param[0] == usize::MAX => {
+            for hartid in 0..=hart::max_hart_id() {
+                if let Some(context) = hart::context(hartid) {
+                    context.stats().reset();
+                }
+            }
+            return Ok(0);
+        }
+        _ => {}
+    }
+    let stats = match hart::context(param[0]) {
+        Some(context) if param[0] <= hart::max_hart_id() => context.stats(),
+        _ => return Err(SBI_ERR_INVALID_PARAM),
+    };
+    match function {
+        FUNCTION_GET_TRAP_COUNT => stats.traps(param[1]).ok_or(SBI_ERR_INVALID_PARAM),
+        FUNCTION_GET_SBI_COUNT => Ok(stats.sbi_calls(param[1], param[2])),
+        FUNCTION_GET_EMULATED_COUNT => stats.emulated(param[1]).ok_or(SBI_ERR_INVALID_PARAM),
+        FUNCTION_GET_M_CYCLES => Ok(stats.cycles()),
+        FUNCTION_RESET_STATS => {
+            stats.reset();
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn control_plane_call(
+    cp: &mut ControlPlane,
+    function: usize,
+    param: [usize; 6],
+) -> Result<usize, usize> {
+    if function == FUNCTION_NUM_LABELS {
+        return Ok(cp.num_labels());
+    }
+    let dsid = param[0];
+    if dsid >= cp.num_labels() {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    match function {
+        FUNCTION_GET_WAY_MASK => Ok(cp.way_mask(dsid)),
+        FUNCTION_SET_WAY_MASK => {
+            let mask = param[1];
+            // 至少要分到一路，不能超出 LLC 的路数
+            let all_ways = 1usize
+                .checked_shl(cp.cache_ways() as u32)
+                .map_or(usize::MAX, |bit| bit - 1);
+            if mask == 0 || mask & !all_ways != 0 {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+            cp.set_way_mask(dsid, mask);
+            Ok(0)
+        }
+        FUNCTION_GET_BUCKET => {
+            let bucket = cp.bucket(dsid);
+            match param[1] {
+                BUCKET_SIZE => Ok(bucket.size),
+                BUCKET_FREQ => Ok(bucket.freq),
+                BUCKET_INC => Ok(bucket.inc),
+                _ => Err(SBI_ERR_INVALID_PARAM),
+            }
+        }
+        FUNCTION_SET_BUCKET => {
+            let bucket = TokenBucket {
+                size: param[1],
+                freq: param[2],
+                inc: param[3],
+            };
+            if bucket.freq == 0 {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+            cp.set_bucket(dsid, bucket);
+            Ok(0)
+        }
+        FUNCTION_GET_STAT => {
+            let stats = cp.stats(dsid);
+            match param[1] {
+                STAT_LLC_HITS => Ok(stats.llc_hits as usize),
+                STAT_LLC_MISSES => Ok(stats.llc_misses as usize),
+                STAT_TRAFFIC => Ok(stats.traffic as usize),
+                _ => Err(SBI_ERR_INVALID_PARAM),
+            }
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+// 没有注册控制平面时返回 Not Supported
+fn with_control_plane(
+    f: impl FnOnce(&mut ControlPlane) -> Result<usize, usize>,
+) -> Result<usize, usize> {
+    match CONTROL_PLANE.lock().as_mut() {
+        Some(cp) => f(cp),
+        None => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+// 每个硬件线程的 DS-id 寄存器，LvNA 的 rocket-chip 中为 procdsid（0x9c0）；
+// 其它核上访问这个 CSR 会产生非法指令异常，只在 board_lrv 下使用
+mod dsid {
+    #[cfg(feature = "board_lrv")]
+    pub fn read() -> Result<usize, usize> {
+        let value: usize;
+        unsafe { core::arch::asm!("csrr {0}, 0x9c0", out(reg) value) };
+        Ok(value)
+    }
+
+    #[cfg(feature = "board_lrv")]
+    pub fn write(value: usize) {
+        unsafe { core::arch::asm!("csrw 0x9c0, {0}", in(reg) value) };
+    }
+
+    #[cfg(not(feature = "board_lrv"))]
+    pub fn read() -> Result<usize, usize> {
+        Err(super::SBI_ERR_NOT_SUPPORTED)
+    }
+
+    #[cfg(not(feature = "board_lrv"))]
+    pub fn write(_value: usize) {}
+}
@@ -0,0 +1,443 @@
+// Performance Monitoring Unit 扩展（PMU）
+//
+// 计数器编号：0 为 cycle，1 为 time（不能配置事件），2 为 instret，3 开始是实现了的 mhpmcounter，
+// 之后是固件计数器。Rocket 没有 mcountinhibit，cycle 和 instret 停止后仍在计数
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+use riscv::register::mhartid;
+use rustsbi::SbiRet;
+use spin::Mutex;
+
+use super::sbi_ret_value::*;
+use crate::hart;
+use crate::MAX_HARTS;
+
+pub const EXTENSION_ID: usize = 0x504D55;
+
+const FUNCTION_NUM_COUNTERS: usize = 0;
+const FUNCTION_COUNTER_GET_INFO: usize = 1;
+const FUNCTION_COUNTER_CONFIG_MATCHING: usize = 2;
+const FUNCTION_COUNTER_START: usize = 3;
+const FUNCTION_COUNTER_STOP: usize = 4;
+const FUNCTION_COUNTER_FW_READ: usize = 5;
+
+mod config_flags {
+    pub const SKIP_MATCH: usize = 1 << 0;
+    pub const CLEAR_VALUE: usize = 1 << 1;
+    pub const AUTO_START: usize = 1 << 2;
+}
+
+mod start_flags {
+    pub const SET_INIT_VALUE: usize = 1 << 0;
+}
+
+mod stop_flags {
+    pub const RESET: usize = 1 << 0;
+}
+
+mod event_type {
+    pub const HARDWARE_GENERAL: usize = 0;
+    pub const HARDWARE_CACHE: usize = 1;
+    pub const HARDWARE_RAW: usize = 2;
+    pub const FIRMWARE: usize = 15;
+}
+
+// Rocket 的 mhpmevent：低 8 位选择事件组，第 8 位开始是组内事件的掩码
+mod rocket {
+    const fn event(set: usize, bit: usize) -> usize {
+        set | (1 << (bit + 8))
+    }
+    pub const LOAD: usize = event(0, 1);
+    pub const STORE: usize = event(0, 2);
+    pub const BRANCH: usize = event(0, 6);
+    pub const ICACHE_BLOCKED: usize = event(1, 3);
+    pub const DCACHE_BLOCKED: usize = event(1, 4);
+    pub const BRANCH_MISPREDICT: usize = event(1, 5);
+    pub const ICACHE_MISS: usize = event(2, 0);
+    pub const DCACHE_MISS: usize = event(2, 1);
+    pub const ITLB_MISS: usize = event(2, 3);
+    pub const DTLB_MISS: usize = event(2, 4);
+}
+
+const FIRST_HPM: usize = 3;
+const MAX_HPM: usize = 29;
+const FIRMWARE_COUNTERS: usize = 8;
+
+/// 固件事件，编号和 SBI PMU 扩展的固件事件一致
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum FirmwareEvent {
+    MisalignedLoad = 0,
+    MisalignedStore = 1,
+    AccessLoad = 2,
+    AccessStore = 3,
+    IllegalInstruction = 4,
+    SetTimer = 5,
+    IpiSent = 6,
+    IpiReceived = 7,
+    /// 平台事件（0xffff）中的 SBI 调用次数，event_data 为 0
+    SbiCall = 8,
+}
+
+pub const FIRMWARE_EVENTS: usize = 9;
+const FIRMWARE_EVENT_PLATFORM: usize = 0xffff;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNTERS_INIT: Mutex<HartCounters> = Mutex::new(HartCounters::new());
+static COUNTERS: [Mutex<HartCounters>; MAX_HARTS] = [COUNTERS_INIT; MAX_HARTS];
+
+/// 当前硬件线程上发生了一次固件事件
+pub fn record(event: FirmwareEvent) {
+    hart::current().fw_events()[event as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// 探测当前硬件线程实现了几个 mhpmcounter，并允许 S 态读取它们；每个硬件线程都要调用
+pub fn init_hart() {
+    let mut counters = match COUNTERS.get(mhartid::read()) {
+        Some(counters) => counters.lock(),
+        None => return,
+    };
+    // 没有实现的计数器恒为零，写入全 1 后读回的位数就是计数器的宽度
+    let mut count = 0;
+    while count < MAX_HPM {
+        let index = FIRST_HPM + count;
+        write_hpmevent(index, 0);
+        write_hpmcounter(index, usize::MAX);
+        let value = read_hpmcounter(index);
+        write_hpmcounter(index, 0);
+        if value == 0 {
+            break;
+        }
+        if count == 0 {
+            counters.hpm_width = value.count_ones() as usize;
+        }
+        count += 1;
+    }
+    counters.hpm_count = count;
+    let hpm_mask = ((1usize << count) - 1) << FIRST_HPM;
+    unsafe { asm!("csrs mcounteren, {0}", in(reg) hpm_mask) };
+}
+
+pub fn handle(function: usize, param: [usize; 6]) -> SbiRet {
+    let mut counters = match COUNTERS.get(mhartid::read()) {
+        Some(counters) => counters.lock(),
+        None => return error(SBI_ERR_NOT_SUPPORTED),
+    };
+    let hart_id = mhartid::read();
+    let result = match function {
+        FUNCTION_NUM_COUNTERS => Ok(counters.num_counters()),
+        FUNCTION_COUNTER_GET_INFO => counters.info(param[0]),
+        FUNCTION_COUNTER_CONFIG_MATCHING => {
+            counters.config_matching(hart_id, param[0], param[1], param[2], param[3], param[4])
+        }
+        FUNCTION_COUNTER_START => counters
+            .start(hart_id, param[0], param[1], param[2], param[3] as u64)
+            .map(|_| 0),
+        FUNCTION_COUNTER_STOP => counters.stop(hart_id, param[0], param[1], param[2]).map(|_| 0),
+        FUNCTION_COUNTER_FW_READ => counters.fw_read(hart_id, param[0]).map(|value| value as usize),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    };
+    match result {
+        Ok(value) => SbiRet::ok(value),
+        Err(e) => error(e),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Event {
+    Cycle,
+    Instret,
+    Hpm(usize),
+    Firmware(usize),
+}
+
+impl Event {
+    fn decode(event_idx: usize, event_data: usize) -> Option<Event> {
+        let code = event_idx & 0xffff;
+        let event = match (event_idx >> 16) & 0xf {
+            event_type::HARDWARE_GENERAL => match code {
+                1 => Event::Cycle,
+                2 => Event::Instret,
+                4 => Event::Hpm(rocket::ICACHE_MISS | rocket::DCACHE_MISS),
+                5 => Event::Hpm(rocket::BRANCH),
+                6 => Event::Hpm(rocket::BRANCH_MISPREDICT),
+                8 => Event::Hpm(rocket::ICACHE_BLOCKED),
+                9 => Event::Hpm(rocket::DCACHE_BLOCKED),
+                _ => return None,
+            },
+            event_type::HARDWARE_CACHE => {
+                // 缓存编号，操作（0 读，1 写），结果（0 访问，1 缺失）
+                // Rocket 不区分读写缺失，缺失都只对应读操作
+                match (code >> 3, (code >> 1) & 0b11, code & 1) {
+                    (0, 0, 0) => Event::Hpm(rocket::LOAD),
+                    (0, 1, 0) => Event::Hpm(rocket::STORE),
+                    (0, 0, 1) => Event::Hpm(rocket::DCACHE_MISS),
+                    (1, 0, 1) => Event::Hpm(rocket::ICACHE_MISS),
+                    (3, 0, 1) => Event::Hpm(rocket::DTLB_MISS),
+                    (4, 0, 1) => Event::Hpm(rocket::ITLB_MISS),
+                    _ => return None,
+                }
+            }
+            // 直接写进 mhpmevent 的值
+            event_type::HARDWARE_RAW if code == 0 && event_data != 0 => Event::Hpm(event_data),
+            event_type::FIRMWARE => match code {
+                FIRMWARE_EVENT_PLATFORM if event_data == 0 => {
+                    Event::Firmware(FirmwareEvent::SbiCall as usize)
+                }
+                code if code < FirmwareEvent::SbiCall as usize => Event::Firmware(code),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FirmwareCounter {
+    event: usize,
+    value: u64,
+    // 开始计数时的事件次数
+    start: usize,
+}
+
+struct HartCounters {
+    hpm_count: usize,
+    hpm_width: usize,
+    // 按计数器编号的位图
+    in_use: u64,
+    started: u64,
+    hpm_event: [usize; MAX_HPM],
+    firmware: [FirmwareCounter; FIRMWARE_COUNTERS],
+}
+
+impl HartCounters {
+    const fn new() -> Self {
+        Self {
+            hpm_count: 0,
+            hpm_width: 0,
+            in_use: 0,
+            started: 0,
+            hpm_event: [0; MAX_HPM],
+            firmware: [FirmwareCounter {
+                event: 0,
+                value: 0,
+                start: 0,
+            }; FIRMWARE_COUNTERS],
+        }
+    }
+
+    fn first_firmware(&self) -> usize {
+        FIRST_HPM + self.hpm_count
+    }
+
+    fn num_counters(&self) -> usize {
+        self.first_firmware() + FIRMWARE_COUNTERS
+    }
+
+    fn info(&self, index: usize) -> Result<usize, usize> {
+        // 硬件计数器：低 12 位为 CSR 编号，12 到 17 位为宽度减一；固件计数器只有最高位
+        let (csr, width) = match index {
+            0..=2 => (0xc00 + index, 64),
+            _ if index < self.first_firmware() => (0xc00 + index, self.hpm_width),
+            _ if index < self.num_counters() => return Ok(1 << (usize::BITS - 1)),
+            _ => return Err(SBI_ERR_INVALID_PARAM),
+        };
+        Ok(csr | ((width - 1) << 12))
+    }
+
+    // 选中的计数器编号位图，有编号超出范围时返回错误
+    fn select(&self, base: usize, mask: usize) -> Result<u64, usize> {
+        let mut selected = 0;
+        for bit in (0..usize::BITS as usize).filter(|bit| mask & (1 << bit) != 0) {
+            match base.checked_add(bit) {
+                Some(index) if index < self.num_counters() => selected |= 1 << index,
+                _ => return Err(SBI_ERR_INVALID_PARAM),
+            }
+        }
+        Ok(selected)
+    }
+
+    fn can_count(&self, index: usize, event: Event) -> bool {
+        match event {
+            Event::Cycle => index == 0,
+            Event::Instret => index == 2,
+            Event::Hpm(_) => (FIRST_HPM..self.first_firmware()).contains(&index),
+            Event::Firmware(_) => (self.first_firmware()..self.num_counters()).contains(&index),
+        }
+    }
+
+    fn config_matching(
+        &mut self,
+        hart_id: usize,
+        base: usize,
+        mask: usize,
+        flags: usize,
+        event_idx: usize,
+        event_data: usize,
+    ) -> Result<usize, usize> {
+        let selected = self.select(base, mask)?;
+        let index = if flags & config_flags::SKIP_MATCH != 0 {
+            // 直接使用已经配置过的计数器
+            let index = bits(selected).next().ok_or(SBI_ERR_INVALID_PARAM)?;
+            if self.in_use & (1 << index) == 0 {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+            index
+        } else {
+            let event = Event::decode(event_idx, event_data).ok_or(SBI_ERR_NOT_SUPPORTED)?;
+            let index = bits(selected)
+                .find(|&index| self.in_use & (1 << index) == 0 && self.can_count(index, event))
+                .ok_or(SBI_ERR_NOT_SUPPORTED)?;
+            match event {
+                Event::Hpm(selector) => self.hpm_event[index - FIRST_HPM] = selector,
+                Event::Firmware(event) => {
+                    self.firmware[index - self.first_firmware()].event = event
+                }
+                Event::Cycle | Event::Instret => {}
+            }
+            self.in_use |= 1 << index;
+            index
+        };
+        if flags & config_flags::CLEAR_VALUE != 0 {
+            self.write(hart_id, index, 0);
+        }
+        if flags & config_flags::AUTO_START != 0 && self.started & (1 << index) == 0 {
+            self.start_one(hart_id, index);
+        }
+        Ok(index)
+    }
+
+    fn start(
+        &mut self,
+        hart_id: usize,
+        base: usize,
+        mask: usize,
+        flags: usize,
+        initial_value: u64,
+    ) -> Result<(), usize> {
+        let selected = self.select(base, mask)?;
+        if selected & !self.in_use != 0 {
+            return Err(SBI_ERR_INVALID_PARAM);
+        }
+        if selected & self.started != 0 {
+            return Err(SBI_ERR_ALREADY_STARTED);
+        }
+        for index in bits(selected) {
+            if flags & start_flags::SET_INIT_VALUE != 0 {
+                self.write(hart_id, index, initial_value);
+            }
+            self.start_one(hart_id, index);
+        }
+        Ok(())
+    }
+
+    fn start_one(&mut self, hart_id: usize, index: usize) {
+        if (FIRST_HPM..self.first_firmware()).contains(&index) {
+            write_hpmevent(index, self.hpm_event[index - FIRST_HPM]);
+        } else if index >= self.first_firmware() {
+            let counter = &mut self.firmware[index - self.first_firmware()];
+            counter.start = tally(hart_id, counter.event);
+        }
+        self.started |= 1 << index;
+    }
+
+    fn stop(&mut self, hart_id: usize, base: usize, mask: usize, flags: usize) -> Result<(), usize> {
+        let selected = self.select(base, mask)?;
+        if selected & !self.in_use != 0 {
+            return Err(SBI_ERR_INVALID_PARAM);
+        }
+        if selected & !self.started != 0 {
+            return Err(SBI_ERR_ALREADY_STOPPED);
+        }
+        for index in bits(selected) {
+            if (FIRST_HPM..self.first_firmware()).contains(&index) {
+                write_hpmevent(index, 0);
+            } else if index >= self.first_firmware() {
+                let value = self.firmware_value(hart_id, index);
+                self.firmware[index - self.first_firmware()].value = value;
+            }
+            self.started &= !(1 << index);
+        }
+        if flags & stop_flags::RESET != 0 {
+            self.in_use &= !selected;
+        }
+        Ok(())
+    }
+
+    fn fw_read(&self, hart_id: usize, index: usize) -> Result<u64, usize> {
+        if !(self.first_firmware()..self.num_counters()).contains(&index) {
+            return Err(SBI_ERR_INVALID_PARAM);
+        }
+        Ok(self.firmware_value(hart_id, index))
+    }
+
+    fn firmware_value(&self, hart_id: usize, index: usize) -> u64 {
+        let counter = &self.firmware[index - self.first_firmware()];
+        if self.started & (1 << index) == 0 {
+            return counter.value;
+        }
+        let now = tally(hart_id, counter.event);
+        counter.value + now.wrapping_sub(counter.start) as u64
+    }
+
+    fn write(&mut self, hart_id: usize, index: usize, value: u64) {
+        match index {
+            0 => unsafe { asm!("csrw mcycle, {0}", in(reg) value) },
+            1 => {}
+            2 => unsafe { asm!("csrw minstret, {0}", in(reg) value) },
+            _ if index < self.first_firmware() => write_hpmcounter(index, value as usize),
+            _ => {
+                let first_firmware = self.first_firmware();
+                let counter = &mut self.firmware[index - first_firmware];
+                counter.value = value;
+                counter.start = tally(hart_id, counter.event);
+            }
+        }
+    }
+}
+
+// 各类固件事件在 `hart_id` 上发生的次数
+fn tally(hart_id: usize, event: usize) -> usize {
+    match hart::context(hart_id) {
+        Some(context) => context.fw_events()[event].load(Ordering::Relaxed),
+        None => 0,
+    }
+}
+
+fn bits(set: u64) -> impl Iterator<Item = usize> {
+    (0..u64::BITS as usize).filter(move |bit| set & (1 << bit) != 0)
+}
+
+fn error(error: usize) -> SbiRet {
+    SbiRet { error, value: 0 }
+}
+
+macro_rules! hpm_csr {
+    ($($i:literal)*) => {
+        fn read_hpmcounter(index: usize) -> usize {
+            let value: usize;
+            match index {
+                $($i => unsafe { asm!(concat!("csrr {0}, mhpmcounter", $i), out(reg) value) },)*
+                _ => panic!("invalid mhpmcounter index {}", index),
+            }
+            value
+        }
+
+        fn write_hpmcounter(index: usize, value: usize) {
+            match index {
+                $($i => unsafe { asm!(concat!("csrw mhpmcounter", $i, ", {0}"), in(reg) value) },)*
+                _ => panic!("invalid mhpmcounter index {}", index),
+            }
+        }
+
+        fn write_hpmevent(index: usize, value: usize) {
+            match index {
+                $($i => unsafe { asm!(concat!("csrw mhpmevent", $i, ", {0}"), in(reg) value) },)*
+                _ => panic!("invalid mhpmevent index {}", index),
+            }
+        }
+    };
+}
+
+hpm_csr!(3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
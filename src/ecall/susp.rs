@@ -0,0 +1,99 @@
+// System Suspend 扩展（SUSP）
+//
+// 只支持挂起到内存：其它硬件线程都已停止时，调用者在 M 态等待串口输入或 S 态时钟到期，
+// 然后从 S 态给出的地址恢复运行。内存一直保持供电，M 态的状态不会丢失
+use core::arch::asm;
+use riscv::asm::wfi;
+use riscv::register::{mhartid, mie, mstatus, satp};
+use rustsbi::SbiRet;
+
+use super::sbi_ret_value::*;
+use crate::hal::{self, Clint, HartStateManager};
+use crate::pmp;
+use crate::TIMEBASE_FREQUENCY;
+
+pub const EXTENSION_ID: usize = 0x53555350;
+
+const FUNCTION_SYSTEM_SUSPEND: usize = 0;
+
+const SLEEP_TYPE_SUSPEND_TO_RAM: usize = 0;
+
+// 挂起期间每隔 10ms 检查一次串口
+const POLL_INTERVAL: u64 = TIMEBASE_FREQUENCY / 100;
+
+pub fn handle(function: usize, param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_SYSTEM_SUSPEND => system_suspend(param[0], param[1], param[2]),
+        _ => error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn system_suspend(sleep_type: usize, resume_addr: usize, opaque: usize) -> SbiRet {
+    if sleep_type != SLEEP_TYPE_SUSPEND_TO_RAM {
+        return error(SBI_ERR_INVALID_PARAM);
+    }
+    if !pmp::supervisor_can_execute(resume_addr) {
+        return error(SBI_ERR_INVALID_ADDRESS);
+    }
+    let hart_id = mhartid::read();
+    let max_hart_id = crate::hart::max_hart_id();
+    if (0..=max_hart_id).any(|hart| hart != hart_id && !HartStateManager::is_stopped(hart)) {
+        return error(SBI_ERR_DENIED);
+    }
+    hal::Uartlite::drain();
+    wait_for_wakeup(hart_id);
+    unsafe {
+        // 恢复时关闭分页和 S 态中断，从 resume_addr 进入 S 态
+        satp::write(0);
+        asm!("sfence.vma");
+        mstatus::clear_sie();
+        mstatus::set_mpp(mstatus::MPP::Supervisor);
+        riscv::register::mepc::write(resume_addr);
+    }
+    // 恢复时 a0 为 hartid，a1 为 opaque，正好是返回值的两个寄存器
+    SbiRet {
+        error: hart_id,
+        value: opaque,
+    }
+}
+
+// 等待串口输入或者 S 态时钟到期
+fn wait_for_wakeup(hart_id: usize) {
+    let clint = hal::clint();
+    let saved_timer = clint.get_mtimecmp(hart_id);
+    // MTIE 关闭说明 S 态时钟已经到期或者没有设置；使用 Sstc 时到期时间在 stimecmp 中
+    let deadline = if Clint::sstc_enabled() {
+        let stimecmp: u64;
+        unsafe { asm!("csrr {0}, 0x14d", out(reg) stimecmp) };
+        stimecmp
+    } else if mie::read().mtimer() {
+        saved_timer
+    } else {
+        u64::MAX
+    };
+    // 只留下 M 态时钟中断用来唤醒，mstatus.MIE 为零，wfi 返回后不会进入中断处理
+    let saved_mie: usize;
+    unsafe { asm!("csrrw {0}, mie, {1}", out(reg) saved_mie, in(reg) 1usize << 7) };
+    loop {
+        let now = clint.get_mtime();
+        if now >= deadline || console_rx_ready() {
+            break;
+        }
+        clint.set_mtimecmp(hart_id, deadline.min(now.saturating_add(POLL_INTERVAL)));
+        unsafe { wfi() };
+    }
+    // 时钟已经到期时，恢复 MTIE 后 M 态时钟中断会照常转给 S 态
+    clint.set_mtimecmp(hart_id, saved_timer);
+    unsafe { asm!("csrw mie, {0}", in(reg) saved_mie) };
+}
+
+fn console_rx_ready() -> bool {
+    match hal::console().as_ref() {
+        Some(serial) => serial.rx_ready(),
+        None => false,
+    }
+}
+
+fn error(error: usize) -> SbiRet {
+    SbiRet { error, value: 0 }
+}
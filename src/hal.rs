@@ -1,13 +1,34 @@
 // Ref: MeowSBI
 
+mod ring_buffer;
+
 mod uartlite;
 pub use uartlite::Uartlite;
 
+mod sifive_uart;
+pub use sifive_uart::SifiveUart;
+
+mod ns16550;
+pub use ns16550::Ns16550;
+
+mod serial;
+pub use serial::{console, init_console, PanicConsole, Serial, SerialStdio};
+
+mod aclint;
+
 mod clint;
-pub use clint::Clint;
+pub use clint::{clint, Clint};
+
+mod plic;
+pub use plic::{plic, Plic};
+
+mod bus_error;
+pub use bus_error::bus_error;
 
 mod hsm;
-pub use hsm::HartStateManager;
+pub use hsm::{hart_state_id, HartStateManager};
+
+pub mod control_plane;
 
 // Ref: https://github.com/repnop/vanadinite/blob/651163fd435d97dc9de728279b64176cdd46ec28/src/arch/virt/mod.rs#L45-L71
 
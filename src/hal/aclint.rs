@@ -0,0 +1,127 @@
+// RISC-V ACLINT 的三个设备，旧的 SiFive CLINT 相当于按固定偏移放在一起的 MSWI 和 MTIMER
+//
+// 设备的地址在 hart 0 读取设备树后才确定，所以都用原子变量保存，全局对象可以在启动后重新指向
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// M 态软件中断设备，每个硬件线程一个 32 位的 msip 寄存器
+pub struct Mswi {
+    base: AtomicUsize,
+}
+
+impl Mswi {
+    pub const fn new(base: usize) -> Mswi {
+        Mswi {
+            base: AtomicUsize::new(base),
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base.load(Ordering::Relaxed)
+    }
+
+    pub fn set_base(&self, base: usize) {
+        self.base.store(base, Ordering::Release);
+    }
+
+    pub fn msip(&self, hart_id: usize) -> *mut u32 {
+        (self.base() as *mut u32).wrapping_add(hart_id)
+    }
+
+    pub fn send(&self, hart_id: usize) {
+        unsafe { write_volatile(self.msip(hart_id), 1) }
+    }
+
+    pub fn clear(&self, hart_id: usize) {
+        unsafe { write_volatile(self.msip(hart_id), 0) }
+    }
+
+    pub fn pending(&self, hart_id: usize) -> bool {
+        unsafe { read_volatile(self.msip(hart_id)) & 1 != 0 }
+    }
+}
+
+/// M 态时钟设备：一个全局的 mtime 和每个硬件线程一个 mtimecmp ，两者的地址可以不相邻
+pub struct Mtimer {
+    mtime: AtomicUsize,
+    mtimecmp: AtomicUsize,
+}
+
+// ACLINT 规范中 MTIMER 设备里 mtime 的默认偏移
+pub const MTIME_OFFSET: usize = 0x7ff8;
+
+impl Mtimer {
+    pub const fn new(mtime: usize, mtimecmp: usize) -> Mtimer {
+        Mtimer {
+            mtime: AtomicUsize::new(mtime),
+            mtimecmp: AtomicUsize::new(mtimecmp),
+        }
+    }
+
+    /// 返回（mtime 地址，mtimecmp 基地址）
+    pub fn base(&self) -> (usize, usize) {
+        (self.mtime.load(Ordering::Relaxed), self.mtimecmp.load(Ordering::Relaxed))
+    }
+
+    pub fn set_base(&self, mtime: usize, mtimecmp: usize) {
+        self.mtime.store(mtime, Ordering::Release);
+        self.mtimecmp.store(mtimecmp, Ordering::Release);
+    }
+
+    pub fn mtimecmp(&self, hart_id: usize) -> *mut u64 {
+        (self.mtimecmp.load(Ordering::Relaxed) as *mut u64).wrapping_add(hart_id)
+    }
+
+    pub fn mtime(&self) -> u64 {
+        unsafe { read_volatile(self.mtime.load(Ordering::Relaxed) as *const u64) }
+    }
+
+    pub fn get_mtimecmp(&self, hart_id: usize) -> u64 {
+        unsafe { read_volatile(self.mtimecmp(hart_id)) }
+    }
+
+    pub fn set_mtimecmp(&self, hart_id: usize, instant: u64) {
+        unsafe { write_volatile(self.mtimecmp(hart_id), instant) }
+    }
+}
+
+/// S 态软件中断设备，写 setssip 直接置位目标硬件线程的 mip.SSIP ，不经过 M 态；
+/// 基地址为 0 表示没有这个设备
+pub struct Sswi {
+    base: AtomicUsize,
+}
+
+impl Sswi {
+    pub const fn new() -> Sswi {
+        Sswi {
+            base: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn base(&self) -> Option<usize> {
+        match self.base.load(Ordering::Relaxed) {
+            0 => None,
+            base => Some(base),
+        }
+    }
+
+    pub fn set_base(&self, base: usize) {
+        self.base.store(base, Ordering::Release);
+    }
+
+    /// `hart_id` 的 setssip 寄存器
+    pub fn setssip(&self, hart_id: usize) -> Option<*mut u32> {
+        self.base().map(|base| (base as *mut u32).wrapping_add(hart_id))
+    }
+
+    /// 没有这个设备时返回 false
+    pub fn send(&self, hart_id: usize) -> bool {
+        match self.setssip(hart_id) {
+            Some(setssip) => {
+                unsafe { write_volatile(setssip, 1) };
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,213 @@
+// Rocket 的总线错误单元（sifive,buserror0）和 TileLink 错误设备（sifive,error0）
+//
+// 每个核有一个总线错误单元，记录最近一次总线错误或 ECC 错误的原因和物理地址，
+// 可以通过 PLIC 报告中断；错误设备则对落在它地址范围内的任何访问都返回错误，
+// 总线上没有映射的地址最终都路由到这里。
+// 总线错误单元的寄存器布局：
+//   0x00 cause          最近一次错误的原因，写 0 清除
+//   0x08 value          最近一次错误的物理地址
+//   0x10 enable         各原因是否记录
+//   0x18 plic_interrupt 各原因是否产生全局中断
+//   0x20 accrued        累计发生过的原因，写 0 清除
+//   0x28 local_interrupt 各原因是否产生核内中断，固件不使用
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::register::mhartid;
+use rustsbi::println;
+
+use super::Plic;
+use crate::dtb;
+use crate::MAX_HARTS;
+
+const CAUSE: usize = 0x00;
+const VALUE: usize = 0x08;
+const ENABLE: usize = 0x10;
+const PLIC_INTERRUPT: usize = 0x18;
+const ACCRUED: usize = 0x20;
+const LOCAL_INTERRUPT: usize = 0x28;
+
+// 原因编号
+const CAUSE_NONE: u64 = 0;
+const CAUSE_ICACHE_CORRECTABLE: u64 = 2;
+const CAUSE_ICACHE_UNCORRECTABLE: u64 = 3;
+const CAUSE_BUS_ERROR: u64 = 5;
+const CAUSE_DCACHE_CORRECTABLE: u64 = 6;
+const CAUSE_DCACHE_UNCORRECTABLE: u64 = 7;
+
+// 记录并报告上面所有的原因
+const CAUSE_MASK: u64 = 1 << CAUSE_ICACHE_CORRECTABLE
+    | 1 << CAUSE_ICACHE_UNCORRECTABLE
+    | 1 << CAUSE_BUS_ERROR
+    | 1 << CAUSE_DCACHE_CORRECTABLE
+    | 1 << CAUSE_DCACHE_UNCORRECTABLE;
+
+pub struct BusError {
+    // 每个硬件线程的总线错误单元，0 表示没有
+    units: [AtomicUsize; MAX_HARTS],
+    // 总线错误单元在 PLIC 上的中断源，0 表示没有
+    irqs: [AtomicUsize; MAX_HARTS],
+    // 错误设备的地址范围，长度为 0 表示没有
+    error_device: (AtomicUsize, AtomicUsize),
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+static BUS_ERROR: BusError = BusError {
+    units: [ZERO; MAX_HARTS],
+    irqs: [ZERO; MAX_HARTS],
+    error_device: (ZERO, ZERO),
+};
+
+pub fn bus_error() -> &'static BusError {
+    &BUS_ERROR
+}
+
+/// 总线错误单元记录的一次错误
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub cause: u64,
+    pub value: usize,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.cause {
+            CAUSE_ICACHE_CORRECTABLE => "instruction cache correctable ECC error",
+            CAUSE_ICACHE_UNCORRECTABLE => "instruction cache uncorrectable ECC error",
+            CAUSE_BUS_ERROR => "TileLink bus error",
+            CAUSE_DCACHE_CORRECTABLE => "data cache correctable ECC error",
+            CAUSE_DCACHE_UNCORRECTABLE => "data cache uncorrectable ECC error",
+            _ => "unknown cause",
+        };
+        write!(f, "cause {} ({}), physical address {:#x}", self.cause, name, self.value)
+    }
+}
+
+impl BusError {
+    /// 读取设备树中的错误设备和总线错误单元，由 hart 0 在启动其它硬件线程之前调用。
+    ///
+    /// 设备树不说明总线错误单元属于哪个核，这里按 Rocket 的布局，地址从小到大依次对应 hart 0、1、……
+    pub unsafe fn probe(&self, dtb_pa: usize) {
+        let dt = match dtb::load(dtb_pa) {
+            Some(dt) => dt,
+            None => return,
+        };
+        if let Some((base, size)) = dtb::find_compatible(&dt, &["sifive,error0"]).and_then(|device| device.reg(0)) {
+            self.error_device.0.store(base, Ordering::Relaxed);
+            self.error_device.1.store(size, Ordering::Relaxed);
+        }
+        let mut units: Vec<(usize, u32)> = dtb::find_all_compatible(&dt, &["sifive,buserror0"])
+            .iter()
+            .filter_map(|device| {
+                let (base, _) = device.reg(0)?;
+                Some((base, device.prop_u32("interrupts").unwrap_or(0)))
+            })
+            .collect();
+        units.sort_unstable();
+        for (hartid, (base, irq)) in units.into_iter().take(MAX_HARTS).enumerate() {
+            self.irqs[hartid].store(irq as usize, Ordering::Relaxed);
+            self.units[hartid].store(base, Ordering::Release);
+        }
+    }
+
+    /// 每个硬件线程启动时调用：清除软启动前留下的记录，打开自己的总线错误单元
+    pub fn init_hart(&self) {
+        let base = match self.unit(mhartid::read()) {
+            Some(base) => base,
+            None => return,
+        };
+        write(base, LOCAL_INTERRUPT, 0);
+        write(base, CAUSE, 0);
+        write(base, ACCRUED, 0);
+        write(base, ENABLE, CAUSE_MASK);
+        write(base, PLIC_INTERRUPT, CAUSE_MASK);
+    }
+
+    /// 在 PLIC 上注册所有总线错误单元的中断，由 hart 0 处理；返回注册成功的个数
+    pub fn enable_interrupts(&self, plic: &Plic) -> usize {
+        self.irqs
+            .iter()
+            .map(|irq| irq.load(Ordering::Relaxed) as u32)
+            .filter(|&irq| irq != 0 && plic.enable(irq, report_interrupt))
+            .count()
+    }
+
+    /// 有总线错误单元的硬件线程个数
+    pub fn units(&self) -> usize {
+        self.units.iter().filter(|base| base.load(Ordering::Relaxed) != 0).count()
+    }
+
+    /// 错误设备的地址范围
+    pub fn error_device(&self) -> Option<(usize, usize)> {
+        match (self.error_device.0.load(Ordering::Relaxed), self.error_device.1.load(Ordering::Relaxed)) {
+            (_, 0) => None,
+            (base, size) => Some((base, size)),
+        }
+    }
+
+    /// 取出并清除 `hartid` 的总线错误单元中的记录
+    pub fn take(&self, hartid: usize) -> Option<Record> {
+        let base = self.unit(hartid)?;
+        let cause = read(base, CAUSE);
+        if cause == CAUSE_NONE {
+            return None;
+        }
+        let value = read(base, VALUE) as usize;
+        write(base, CAUSE, 0);
+        write(base, ACCRUED, 0);
+        Some(Record { cause, value })
+    }
+
+    /// 访存异常时调用：打印总线错误单元的记录和出错地址的归属，
+    /// 记录被取出后中断处理就不会重复打印。`physical` 表示 `addr` 是否为物理地址
+    pub fn report_fault(&self, kind: &str, addr: usize, physical: bool, mepc: usize) {
+        let hartid = mhartid::read();
+        println!(
+            "[rustsbi-beu] hart {} {} access fault at {:#x}, mepc {:#x}",
+            hartid, kind, addr, mepc
+        );
+        let record = self.take(hartid);
+        if let Some(record) = record {
+            println!("[rustsbi-beu] {}", record);
+        }
+        // 优先用总线错误单元记下的物理地址判断
+        let paddr = match record {
+            Some(record) if record.cause == CAUSE_BUS_ERROR => Some(record.value),
+            _ if physical => Some(addr),
+            _ => None,
+        };
+        if let (Some(paddr), Some((base, size))) = (paddr, self.error_device()) {
+            if paddr >= base && paddr - base < size {
+                println!("[rustsbi-beu] address is in the TileLink error device, nothing is mapped there");
+            }
+        }
+    }
+
+    fn unit(&self, hartid: usize) -> Option<usize> {
+        match self.units.get(hartid)?.load(Ordering::Relaxed) {
+            0 => None,
+            base => Some(base),
+        }
+    }
+}
+
+// PLIC 中断处理：打印所有总线错误单元中没有被访存异常取走的记录，主要是缓存的 ECC 错误
+fn report_interrupt() {
+    for hartid in 0..MAX_HARTS {
+        if let Some(record) = BUS_ERROR.take(hartid) {
+            println!("[rustsbi-beu] hart {} {}", hartid, record);
+        }
+    }
+}
+
+fn read(base: usize, offset: usize) -> u64 {
+    unsafe { read_volatile((base + offset) as *const u64) }
+}
+
+fn write(base: usize, offset: usize, value: u64) {
+    unsafe { write_volatile((base + offset) as *mut u64, value) }
+}
@@ -1,67 +1,247 @@
 // 这部分其实是运行时提供的，不应该做到实现库里面
-use rustsbi::SbiRet;
+//
+// 全局只有一个 CLINT 驱动对象，IPI、时钟、HSM 唤醒和 rdtime 模拟都通过 `clint()` 使用它，
+// 底下可以是旧的 SiFive CLINT ，也可以是分开的 ACLINT 设备。
+// msip 和 mtimecmp 按硬件线程分开，每个寄存器都是一次对齐的读写，不需要加锁：
+// mtimecmp 只由所属的硬件线程写，msip 由其它硬件线程置位、所属的硬件线程清零
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::{mhartid, mie, mip};
+use rustsbi::{println, SbiRet};
 
+use super::aclint::{Mswi, Mtimer, Sswi, MTIME_OFFSET};
+use crate::dtb;
+
+// hart 0 按设备树确定了设备地址；在这之前其它硬件线程不能访问 msip
+static PROBED: AtomicBool = AtomicBool::new(false);
+
+// 所有硬件线程都支持 Sstc 时，S 态时钟中断直接由 stimecmp 产生，不再经过 M 态
+static SSTC: AtomicBool = AtomicBool::new(false);
+
+// menvcfg（0x30a）的 STCE 位，打开后 S 态可以访问 stimecmp（0x14d）
+const MENVCFG_STCE: usize = 1 << 63;
+
+// 板上 CLINT 的地址，与设备树一致；设备树中给出别的地址或者 ACLINT 时由 `probe` 修改
+const CLINT_BASE: usize = 0x200_0000;
+// SiFive CLINT 中 mtimecmp 和 mtime 的偏移
+const CLINT_MTIMECMP_OFFSET: usize = 0x4000;
+const CLINT_MTIME_OFFSET: usize = 0xbff8;
+
+static CLINT: Clint = Clint::legacy(CLINT_BASE);
+
+/// 全局的 CLINT 驱动对象
+pub fn clint() -> &'static Clint {
+    &CLINT
+}
+
+/// SiFive CLINT ，或者 ACLINT 的 MSWI、MTIMER 和可选的 SSWI
 pub struct Clint {
-    base: usize,
+    mswi: Mswi,
+    mtimer: Mtimer,
+    sswi: Sswi,
 }
 
 impl Clint {
-    pub fn new(base: *mut u8) -> Clint {
+    /// 使用 Sstc；在启动其它硬件线程之前由 hart 0 根据设备树调用
+    pub fn enable_sstc() {
+        SSTC.store(true, Ordering::Release);
+    }
+
+    pub fn sstc_enabled() -> bool {
+        SSTC.load(Ordering::Acquire)
+    }
+
+    /// 每个硬件线程初始化时调用：使用 Sstc 时允许 S 态访问 stimecmp，并推迟它的时钟中断
+    pub fn init_hart_timer() {
+        if Self::sstc_enabled() {
+            unsafe {
+                asm!("csrs 0x30a, {0}", in(reg) MENVCFG_STCE);
+                asm!("csrw 0x14d, {0}", in(reg) u64::MAX);
+            }
+        }
+    }
+
+    const fn legacy(base: usize) -> Clint {
         Clint {
-            base: base as usize,
+            mswi: Mswi::new(base),
+            mtimer: Mtimer::new(base + CLINT_MTIME_OFFSET, base + CLINT_MTIMECMP_OFFSET),
+            sswi: Sswi::new(),
         }
     }
 
-    pub fn get_mtime(&self) -> u64 {
-        unsafe {
-            let base = self.base as *mut u8;
-            core::ptr::read_volatile(base.add(0xbff8) as *mut u64)
+    /// 按设备树修改设备地址，在启动其它硬件线程之前由 hart 0 调用：
+    ///
+    /// - `riscv,clint0` 或 `sifive,clint0`：旧的 CLINT ；
+    /// - 否则找 `riscv,aclint-mswi` 和 `riscv,aclint-mtimer` ，MTIMER 有 `reg-names` 时按名字
+    ///   `mtime` 和 `mtimecmp` 选择区域；否则按 ACLINT 绑定的顺序，`reg` 有两项时第一项是 mtimecmp ，
+    ///   最后一项是 mtime ，只有一项时 mtime 在默认的偏移处；
+    /// - `riscv,aclint-sswi` 可选。
+    ///
+    /// 有多个同类设备时只使用第一个，并且假定硬件线程从 0 开始编号；
+    /// 其它硬件线程在这之前等待 IPI 时只依赖 mip.MSIP ，要等 [`Clint::probed`] 之后才能访问设备。
+    /// 确定地址后清除所有硬件线程的 msip
+    pub unsafe fn probe(&self, dtb_pa: usize) {
+        self.probe_devices(dtb_pa);
+        // 软复位之前留下的软件中断会被等待的硬件线程当作 legacy SBI 的唤醒，在公布地址之前清除；
+        // 这时还没有谁会发 IPI
+        for hartid in 0..=hart::compiled_max_hart_id() {
+            self.clear_soft(hartid);
         }
+        PROBED.store(true, Ordering::Release);
     }
 
-    pub fn set_timer(&self, hart_id: usize, instant: u64) {
-        unsafe {
-            let base = self.base as *mut u8;
-            core::ptr::write_volatile((base.offset(0x4000) as *mut u64).add(hart_id), instant);
+    /// hart 0 是否已经确定了设备地址
+    pub fn probed() -> bool {
+        PROBED.load(Ordering::Acquire)
+    }
+
+    unsafe fn probe_devices(&self, dtb_pa: usize) {
+        let dt = match dtb::load(dtb_pa) {
+            Some(dt) => dt,
+            None => return,
+        };
+        if let Some((base, _)) =
+            dtb::find_compatible(&dt, &["riscv,clint0", "sifive,clint0"]).and_then(|device| device.reg(0))
+        {
+            self.mswi.set_base(base);
+            self.mtimer
+                .set_base(base + CLINT_MTIME_OFFSET, base + CLINT_MTIMECMP_OFFSET);
+        } else {
+            if let Some((base, _)) =
+                dtb::find_compatible(&dt, &["riscv,aclint-mswi"]).and_then(|device| device.reg(0))
+            {
+                self.mswi.set_base(base);
+            }
+            if let Some(device) = dtb::find_compatible(&dt, &["riscv,aclint-mtimer"]) {
+                let named = (device.reg_by_name("mtime"), device.reg_by_name("mtimecmp"));
+                match (named, device.reg(0), device.reg(1)) {
+                    ((Some((mtime, _)), Some((mtimecmp, _))), _, _) => self.mtimer.set_base(mtime, mtimecmp),
+                    // ACLINT 绑定中 mtimecmp 在前， mtime 在后
+                    (_, Some((mtimecmp, _)), Some((mtime, _))) => self.mtimer.set_base(mtime, mtimecmp),
+                    (_, Some((mtimecmp, _)), None) => self.mtimer.set_base(mtimecmp + MTIME_OFFSET, mtimecmp),
+                    _ => {}
+                }
+            }
+        }
+        if let Some((base, _)) =
+            dtb::find_compatible(&dt, &["riscv,aclint-sswi"]).and_then(|device| device.reg(0))
+        {
+            self.sswi.set_base(base);
         }
     }
 
+    /// 打印使用的设备地址
+    pub fn print_config(&self) {
+        let (mtime, mtimecmp) = self.mtimer.base();
+        println!(
+            "[rustsbi] MSWI: {:#x}, MTIMER: mtime {:#x} mtimecmp {:#x}",
+            self.mswi.base(),
+            mtime,
+            mtimecmp
+        );
+        if let Some(sswi) = self.sswi.base() {
+            println!("[rustsbi] SSWI: {:#x}, supervisor IPIs bypass M-mode", sswi);
+        }
+    }
+
+    pub fn get_mtime(&self) -> u64 {
+        self.mtimer.mtime()
+    }
+
+    pub fn set_mtimecmp(&self, hart_id: usize, instant: u64) {
+        self.mtimer.set_mtimecmp(hart_id, instant)
+    }
+
+    pub fn get_mtimecmp(&self, hart_id: usize) -> u64 {
+        self.mtimer.get_mtimecmp(hart_id)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_soft(&self, hart_id: usize) -> bool {
+        self.mswi.pending(hart_id)
+    }
+
     pub fn send_soft(&self, hart_id: usize) {
-        unsafe {
-            let base = self.base as *mut u8;
-            core::ptr::write_volatile((base as *mut u32).add(hart_id), 1);
+        self.mswi.send(hart_id)
+    }
+
+    pub fn clear_soft(&self, hart_id: usize) {
+        self.mswi.clear(hart_id)
+    }
+
+    /// 每个硬件线程在 `init_hart_timer` 之后调用：设备地址已经确定，
+    /// M 态软件中断和 set_timer 可以走 trap.S 中的快速路径；使用 Sstc 时 set_timer 仍走完整的处理
+    pub fn init_hart_fast_path(&self) {
+        let hart_id = mhartid::read();
+        let context = hart::current();
+        context.set_msip(self.mswi.msip(hart_id) as usize);
+        if !Self::sstc_enabled() {
+            let (mtime, _) = self.mtimer.base();
+            context.set_fast_timer(mtime, self.mtimer.mtimecmp(hart_id) as usize);
         }
     }
 
-    pub fn clear_soft(&mut self, hart_id: usize) {
-        unsafe {
-            let base = self.base as *mut u8;
-            core::ptr::write_volatile((base as *mut u32).add(hart_id), 0);
+    /// 按最大的硬件线程编号填写 send_ipi 快速路径的目标，由 hart 0 在读取设备树之后调用
+    pub fn init_fast_ipi(&self) {
+        for i in 0..=hart::max_hart_id() {
+            match self.sswi.setssip(i) {
+                Some(setssip) => hart::set_ipi_target(i, setssip as usize, false),
+                None => hart::set_ipi_target(i, self.mswi.msip(i) as usize, true),
+            }
         }
     }
 }
 
+use crate::ecall::pmu::{self, FirmwareEvent};
+use crate::hart;
 use rustsbi::{HartMask, Ipi, Timer};
 
-impl Ipi for Clint {
+// rustsbi 需要拥有实现，交给它的是全局对象的引用
+impl Ipi for &'static Clint {
     fn max_hart_id(&self) -> usize {
         // 这个值将在初始化的时候加载，会从dtb_pa读取设备树，然后数里面有几个核
-        *crate::MAX_HART_ID.lock()
+        crate::hart::max_hart_id()
     }
 
     fn send_ipi_many(&self, hart_mask: HartMask) -> SbiRet {
         for i in 0..=self.max_hart_id() {
             if hart_mask.has_bit(i) {
-                self.send_soft(i);
+                // 有 SSWI 时直接置位对方的 SSIP ，否则留言后经过对方的 M 态软件中断转发
+                if !self.sswi.send(i) {
+                    if let Some(context) = hart::context(i) {
+                        context.post_ipi(hart::ipi::SSIP);
+                    }
+                    self.send_soft(i);
+                }
+                pmu::record(FirmwareEvent::IpiSent);
             }
         }
         SbiRet::ok(0)
     }
 }
 
-impl Timer for Clint {
+impl Timer for &'static Clint {
     fn set_timer(&self, time_value: u64) {
-        let this_mhartid = riscv::register::mhartid::read();
-        self.set_timer(this_mhartid, time_value);
+        pmu::record(FirmwareEvent::SetTimer);
+        if Clint::sstc_enabled() {
+            // 写 stimecmp 同时会按新的时间更新 STIP
+            unsafe { asm!("csrw 0x14d, {0}", in(reg) time_value) };
+            return;
+        }
+        let this_mhartid = mhartid::read();
+        unsafe {
+            // 上一次的时钟中断已经处理完了，新的时间到了之前不应该再有 STIP
+            mip::clear_stimer();
+            if time_value <= self.get_mtime() {
+                // 时间已经过去，直接给 S 态时钟中断，不必再经过 M 态时钟中断
+                self.set_mtimecmp(this_mhartid, u64::MAX);
+                mie::clear_mtimer();
+                mip::set_stimer();
+            } else {
+                self.set_mtimecmp(this_mhartid, time_value);
+                // M 态时钟中断处理时会关掉 MTIE，每次设置时钟都要重新打开
+                mie::set_mtimer();
+            }
+        }
     }
 }
@@ -0,0 +1,49 @@
+// 标签化 RISC-V 的控制平面，寄存器读写在 control-plane 库中，这里从设备树读取地址和默认划分
+use alloc::vec::Vec;
+
+use control_plane::{ControlPlane, LlcControlPlane, MemControlPlane, TokenBucket};
+
+use crate::dtb;
+
+/// 从设备树中读取控制平面并写入默认划分：
+///
+/// - `lrv,llc-control-plane`：`reg` 为寄存器区域，`lrv,labels` 标签数，`lrv,ways` 路数，
+///   可选的 `lrv,default-way-masks` 为每个标签的默认路掩码；
+/// - `lrv,mem-control-plane`：`reg` 为寄存器区域，`lrv,labels` 标签数，
+///   可选的 `lrv,default-buckets` 为每个标签的默认 `<size freq inc>` 。
+///
+/// 两个都没有时返回 None
+pub unsafe fn probe(dtb_pa: usize) -> Option<ControlPlane> {
+    let dt = dtb::load(dtb_pa)?;
+    let llc = dtb::find_compatible(&dt, &["lrv,llc-control-plane"]).and_then(|device| {
+        let (base, size) = device.reg(0)?;
+        let labels = device.prop_u32("lrv,labels")? as usize;
+        let ways = device.prop_u32("lrv,ways")? as usize;
+        Some(LlcControlPlane::new(base, size, labels, ways))
+    });
+    let mem = dtb::find_compatible(&dt, &["lrv,mem-control-plane"]).and_then(|device| {
+        let (base, size) = device.reg(0)?;
+        let labels = device.prop_u32("lrv,labels")? as usize;
+        Some(MemControlPlane::new(base, size, labels))
+    });
+    if llc.is_none() && mem.is_none() {
+        return None;
+    }
+    let way_masks: Vec<usize> = dtb::find_compatible(&dt, &["lrv,llc-control-plane"])
+        .map(|device| device.prop_u32s("lrv,default-way-masks").map(|mask| mask as usize).collect())
+        .unwrap_or_default();
+    let cells: Vec<u32> = dtb::find_compatible(&dt, &["lrv,mem-control-plane"])
+        .map(|device| device.prop_u32s("lrv,default-buckets").collect())
+        .unwrap_or_default();
+    let buckets: Vec<TokenBucket> = cells
+        .chunks_exact(3)
+        .map(|cell| TokenBucket {
+            size: cell[0] as usize,
+            freq: cell[1] as usize,
+            inc: cell[2] as usize,
+        })
+        .collect();
+    let mut control_plane = ControlPlane { llc, mem };
+    control_plane.apply_defaults(&way_masks, &buckets);
+    Some(control_plane)
+}
@@ -1,6 +1,24 @@
-use super::clint::Clint;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use riscv::asm::wfi;
+use riscv::register::{mepc, mhartid, mie, mip, mstatus, satp};
 use rustsbi::SbiRet;
 
+use super::clint::{clint, Clint};
+use crate::ecall::sbi_ret_value;
+use crate::{hart, pmp};
+
+// 各硬件线程的状态保存在上下文中；启动时除 hart 0 外都在 mp_hook 中等待，处于停止状态，
+// HART start 或者 legacy SBI 的 IPI 都可以唤醒。
+// HART stop 之后同样在 M 态等待，只有其它硬件线程用 HART start 给出新的入口才会唤醒
+fn hart_state(hartid: usize) -> Option<&'static AtomicU8> {
+    match hart::context(hartid) {
+        Some(context) if hartid <= hart::max_hart_id() => Some(context.hsm_state()),
+        _ => None,
+    }
+}
+
 #[allow(dead_code)]
 pub struct HartStateManager {
     hart_state: HartState,
@@ -12,31 +30,138 @@ impl HartStateManager {
             hart_state: HartState::Started,
         }
     }
+
+    /// 当前硬件线程离开 mp_hook，开始运行
+    pub fn mark_started() {
+        hart::current().hsm_state().store(hart_state_id::STARTED, Ordering::Release);
+    }
+
+    /// 停止状态下等待 HART start 的请求，返回请求中的入口和 opaque ；它们也留在上下文中。
+    ///
+    /// `legacy_wakeup` 为 true 时（启动时在 mp_hook 中等待），没有启动请求的 M 态软件中断也会唤醒，
+    /// 返回 None ：不使用 HSM 的内核按 legacy SBI 的做法用 send_ipi 唤醒其它硬件线程，它们从默认的内核入口启动。
+    /// 否则其它原因的 M 态软件中断，比如发给停止的硬件线程的 IPI ，清除后继续等待。
+    ///
+    /// 只打开 M 态软件中断，mstatus.MIE 为零，wfi 返回后不会进入中断处理。
+    /// 启动时 hart 0 可能还没有确定 CLINT 的地址，这时只看启动请求，不访问 msip ：
+    /// 之前留下的软件中断会让 wfi 立即返回，在地址确定之前空转；hart 0 确定地址时会清除它们
+    pub fn wait_for_start(legacy_wakeup: bool) -> Option<(usize, usize)> {
+        let hartid = mhartid::read();
+        let context = hart::current();
+        let clint = clint();
+        let saved_mie: usize;
+        unsafe { asm!("csrrw {0}, mie, {1}", out(reg) saved_mie, in(reg) 1usize << 3) };
+        let start = loop {
+            let woken = mip::read().msoft() && Clint::probed();
+            if woken {
+                clint.clear_soft(hartid);
+            }
+            // hart_start 先留下请求再发软件中断，清除之后再取请求，不会漏掉
+            if let Some(start) = context.take_start_request() {
+                break Some(start);
+            }
+            if woken && legacy_wakeup {
+                break None;
+            }
+            unsafe { wfi() };
+        };
+        // 停止期间留下的消息属于上一次运行，丢掉
+        context.take_ipi();
+        unsafe {
+            mip::clear_ssoft();
+            asm!("csrw mie, {0}", in(reg) saved_mie);
+        }
+        start
+    }
+
+    pub fn is_stopped(hartid: usize) -> bool {
+        match hart_state(hartid) {
+            Some(state) => state.load(Ordering::Acquire) == hart_state_id::STOPPED,
+            None => true,
+        }
+    }
 }
 
-#[allow(unused_variables)]
 impl rustsbi::Hsm for HartStateManager {
     fn hart_start(&self, hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
-        let clint = Clint::new(0x2000000 as *mut u8);
-        clint.send_soft(hartid);
+        let (state, context) = match (hart_state(hartid), hart::context(hartid)) {
+            (Some(state), Some(context)) => (state, context),
+            _ => {
+                return SbiRet {
+                    error: sbi_ret_value::SBI_ERR_INVALID_PARAM,
+                    value: 0,
+                }
+            }
+        };
+        if !pmp::supervisor_can_execute(start_addr) {
+            return SbiRet {
+                error: sbi_ret_value::SBI_ERR_INVALID_ADDRESS,
+                value: 0,
+            };
+        }
+        let stopped = state.compare_exchange(
+            hart_state_id::STOPPED,
+            hart_state_id::START_PENDING,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        if stopped.is_err() {
+            return SbiRet {
+                error: sbi_ret_value::SBI_ERR_ALREADY_AVAILABLE,
+                value: 0,
+            };
+        }
+        context.request_start(start_addr, opaque);
+        clint().send_soft(hartid);
         SbiRet::ok(0)
     }
+    // 只能停止调用者自己；在陷入处理中等待，再次启动时从这次调用返回到新的入口
     fn hart_stop(&self, hartid: usize) -> SbiRet {
+        // 停止期间不需要 S 态时钟
+        let clint = clint();
+        clint.set_mtimecmp(hartid, u64::MAX);
+        unsafe {
+            if Clint::sstc_enabled() {
+                asm!("csrw 0x14d, {0}", in(reg) u64::MAX);
+            }
+            mie::clear_mtimer();
+            mip::clear_stimer();
+        }
+        hart::current()
+            .hsm_state()
+            .store(hart_state_id::STOPPED, Ordering::Release);
+        // 不接受 legacy 唤醒，只会带着启动请求返回
+        let (start_addr, opaque) = Self::wait_for_start(false).unwrap_or_default();
+        Self::mark_started();
+        unsafe {
+            // 和启动时一样关闭分页和 S 态中断，从 start_addr 进入 S 态
+            satp::write(0);
+            asm!("sfence.vma");
+            mstatus::clear_sie();
+            mstatus::set_mpp(mstatus::MPP::Supervisor);
+            mepc::write(start_addr);
+        }
+        // a0 为 hartid，a1 为 opaque，正好是返回值的两个寄存器
         SbiRet {
-            error: sbi_ret_value::SBI_ERR_NOT_SUPPORTED,
-            value: 0,
+            error: hartid,
+            value: opaque,
         }
     }
     fn hart_get_status(&self, hartid: usize) -> SbiRet {
-        SbiRet {
-            error: sbi_ret_value::SBI_ERR_NOT_SUPPORTED,
-            value: 0,
+        match hart_state(hartid) {
+            Some(state) => {
+                SbiRet::ok(state.load(Ordering::Acquire) as usize)
+            }
+            _ => SbiRet {
+                error: sbi_ret_value::SBI_ERR_INVALID_PARAM,
+                value: 0,
+            },
         }
     }
 }
 
 #[allow(dead_code)]
-mod hart_state_id {
+pub mod hart_state_id {
     pub const STARTED: u8 = 0;
     pub const STOPPED: u8 = 1;
     pub const START_PENDING: u8 = 2;
@@ -56,14 +181,3 @@ pub enum HartState {
     SuspendedPending,
     ResumePending,
 }
-
-#[allow(dead_code)]
-mod sbi_ret_value {
-    pub const SBI_SUCCESS: usize = 0;
-    pub const SBI_ERR_FAILED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-1));
-    pub const SBI_ERR_NOT_SUPPORTED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-2));
-    pub const SBI_ERR_INVALID_PARAM: usize = usize::from_ne_bytes(isize::to_ne_bytes(-3));
-    pub const SBI_ERR_DENIED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-4));
-    pub const SBI_ERR_INVALID_ADDRESS: usize = usize::from_ne_bytes(isize::to_ne_bytes(-5));
-    pub const SBI_ERR_ALREADY_AVAILABLE: usize = usize::from_ne_bytes(isize::to_ne_bytes(-6));
-}
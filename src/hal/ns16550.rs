@@ -0,0 +1,149 @@
+// NS16550A 兼容串口，ns16550a
+use core::convert::Infallible;
+use core::ptr::{read_volatile, write_volatile};
+use embedded_hal::serial::{Read, Write};
+
+use super::serial::Error;
+
+#[derive(Clone)]
+pub struct Ns16550 {
+    base: usize,
+    // 寄存器间隔为 1 << shift 字节
+    shift: usize,
+    // 寄存器访问宽度，1 或 4 字节
+    io_width: usize,
+}
+
+impl Ns16550 {
+    /// `clock_frequency` 为 None 时保留原来的分频设置
+    pub fn new(
+        base: usize,
+        shift: usize,
+        io_width: usize,
+        clock_frequency: Option<u32>,
+        baud_rate: u32,
+    ) -> Self {
+        let uart = Self {
+            base,
+            shift,
+            io_width,
+        };
+        // 关闭中断
+        uart.write_reg(offsets::IER, 0);
+        if let Some(clock_frequency) = clock_frequency {
+            // 波特率 = 时钟频率 / (16 * 除数)，四舍五入
+            let divisor = (clock_frequency + 8 * baud_rate) / (16 * baud_rate);
+            uart.write_reg(offsets::LCR, masks::LCR_DLAB);
+            uart.write_reg(offsets::DLL, divisor as u8);
+            uart.write_reg(offsets::DLM, (divisor >> 8) as u8);
+        }
+        // 8 位数据，无校验，1 位停止位
+        uart.write_reg(offsets::LCR, masks::LCR_8N1);
+        // 打开并清空 FIFO
+        uart.write_reg(offsets::FCR, masks::FCR_ENABLE | masks::FCR_CLEAR);
+        uart.write_reg(offsets::MCR, masks::MCR_DTR | masks::MCR_RTS);
+        uart
+    }
+
+    /// 接收 FIFO 中是否有数据，不取出
+    pub fn rx_ready(&self) -> bool {
+        self.read_reg(offsets::LSR) & masks::LSR_DR != 0
+    }
+
+    fn read_reg(&self, offset: usize) -> u8 {
+        let addr = self.base + (offset << self.shift);
+        unsafe {
+            match self.io_width {
+                4 => read_volatile(addr as *const u32) as u8,
+                _ => read_volatile(addr as *const u8),
+            }
+        }
+    }
+
+    fn write_reg(&self, offset: usize, value: u8) {
+        let addr = self.base + (offset << self.shift);
+        unsafe {
+            match self.io_width {
+                4 => write_volatile(addr as *mut u32, value as u32),
+                _ => write_volatile(addr as *mut u8, value),
+            }
+        }
+    }
+
+    fn write_byte(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        if self.read_reg(offsets::LSR) & masks::LSR_THRE == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.write_reg(offsets::THR, word);
+        Ok(())
+    }
+}
+
+impl Read<u8> for Ns16550 {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        // 错误位在读 LSR 后自动清零
+        let lsr = self.read_reg(offsets::LSR);
+        if lsr & masks::LSR_OE != 0 {
+            return Err(nb::Error::Other(Error::Overrun));
+        }
+        if lsr & masks::LSR_FE != 0 {
+            return Err(nb::Error::Other(Error::Frame));
+        }
+        if lsr & masks::LSR_PE != 0 {
+            return Err(nb::Error::Other(Error::Parity));
+        }
+        if lsr & masks::LSR_DR != 0 {
+            Ok(self.read_reg(offsets::RBR))
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl Write<u8> for Ns16550 {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        if word == b'\n' {
+            nb::block!(self.write_byte(b'\r'))?;
+        }
+        self.write_byte(word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        if self.read_reg(offsets::LSR) & masks::LSR_TEMT != 0 {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+mod offsets {
+    pub const RBR: usize = 0;
+    pub const THR: usize = 0;
+    pub const DLL: usize = 0;
+    pub const IER: usize = 1;
+    pub const DLM: usize = 1;
+    pub const FCR: usize = 2;
+    pub const LCR: usize = 3;
+    pub const MCR: usize = 4;
+    pub const LSR: usize = 5;
+}
+
+mod masks {
+    pub const LCR_DLAB: u8 = 0x80;
+    pub const LCR_8N1: u8 = 0x03;
+    pub const FCR_ENABLE: u8 = 0x01;
+    pub const FCR_CLEAR: u8 = 0x06;
+    pub const MCR_DTR: u8 = 0x01;
+    pub const MCR_RTS: u8 = 0x02;
+    pub const LSR_DR: u8 = 0x01;
+    pub const LSR_OE: u8 = 0x02;
+    pub const LSR_PE: u8 = 0x04;
+    pub const LSR_FE: u8 = 0x08;
+    pub const LSR_THRE: u8 = 0x20;
+    pub const LSR_TEMT: u8 = 0x40;
+}
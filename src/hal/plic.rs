@@ -0,0 +1,228 @@
+// 平台级中断控制器（riscv,plic0）
+//
+// 固件只使用 M 态上下文处理自己的设备（如控制台接收），S 态上下文在启动时清理干净后留给内核配置。
+// 寄存器布局：
+//   0x000000 每个中断源的优先级
+//   0x002000 每个上下文的使能位，每个上下文 0x80 字节
+//   0x200000 每个上下文的阈值，0x200004 为认领/完成，每个上下文 0x1000 字节
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::register::{mhartid, mie};
+use rustsbi::println;
+use spin::Mutex;
+
+use crate::dtb;
+use crate::MAX_HARTS;
+
+const PRIORITY_BASE: usize = 0x0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+// 规范允许的最大中断源数，0 号保留
+const MAX_SOURCES: usize = 1024;
+// 固件自己处理的中断源个数上限
+const MAX_HANDLERS: usize = 8;
+
+// mip/mie 中 M 态和 S 态外部中断的编号
+const IRQ_M_EXT: u32 = 11;
+const IRQ_S_EXT: u32 = 9;
+
+const NO_CONTEXT: usize = usize::MAX;
+
+pub struct Plic {
+    // 为 0 表示没有 PLIC
+    base: AtomicUsize,
+    ndev: AtomicUsize,
+    m_context: [AtomicUsize; MAX_HARTS],
+    s_context: [AtomicUsize; MAX_HARTS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CONTEXT_ATOMIC: AtomicUsize = AtomicUsize::new(NO_CONTEXT);
+
+static PLIC: Plic = Plic {
+    base: AtomicUsize::new(0),
+    ndev: AtomicUsize::new(0),
+    m_context: [NO_CONTEXT_ATOMIC; MAX_HARTS],
+    s_context: [NO_CONTEXT_ATOMIC; MAX_HARTS],
+};
+
+// 固件注册的中断源和处理函数
+type Handler = (u32, fn());
+
+static HANDLERS: Mutex<[Option<Handler>; MAX_HANDLERS]> = Mutex::new([None; MAX_HANDLERS]);
+
+/// 设备树中有 PLIC 时返回全局的驱动对象
+pub fn plic() -> Option<&'static Plic> {
+    match PLIC.base.load(Ordering::Acquire) {
+        0 => None,
+        _ => Some(&PLIC),
+    }
+}
+
+impl Plic {
+    /// 读取设备树中的 PLIC ，由 hart 0 在启动其它硬件线程之前调用，并把所有中断源的优先级清零。
+    ///
+    /// 上下文按 `interrupts-extended` 确定，原因为 11 的是 M 态，9 的是 S 态；
+    /// 找不到对应的 CPU 时按 Rocket 的布局，hart i 的 M 态上下文为 2i ，S 态为 2i+1
+    pub unsafe fn probe(dtb_pa: usize) {
+        let dt = match dtb::load(dtb_pa) {
+            Some(dt) => dt,
+            None => return,
+        };
+        let device = match dtb::find_compatible(&dt, &["riscv,plic0", "sifive,plic-1.0.0"]) {
+            Some(device) => device,
+            None => return,
+        };
+        let base = match device.reg(0) {
+            Some((base, _)) => base,
+            None => return,
+        };
+        let ndev = (device.prop_u32("riscv,ndev").unwrap_or(0) as usize).min(MAX_SOURCES - 1);
+        for (context, (hartid, cause)) in dtb::hart_interrupts(&dt, &device).into_iter().enumerate() {
+            let hartid = hartid.unwrap_or(context / 2);
+            if hartid >= MAX_HARTS {
+                continue;
+            }
+            match cause {
+                IRQ_M_EXT => PLIC.m_context[hartid].store(context, Ordering::Relaxed),
+                IRQ_S_EXT => PLIC.s_context[hartid].store(context, Ordering::Relaxed),
+                _ => {}
+            }
+        }
+        PLIC.ndev.store(ndev, Ordering::Relaxed);
+        PLIC.base.store(base, Ordering::Release);
+        for source in 1..=ndev {
+            PLIC.set_priority(source as u32, 0);
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base.load(Ordering::Relaxed)
+    }
+
+    pub fn ndev(&self) -> usize {
+        self.ndev.load(Ordering::Relaxed)
+    }
+
+    /// 每个硬件线程启动时调用：关闭自己两个上下文的全部使能，阈值清零，
+    /// 并完成软启动之前可能没有完成的中断，使内核拿到的 S 态上下文处于复位后的状态
+    pub fn init_hart(&self) {
+        let hartid = mhartid::read();
+        for context in [self.m_context(hartid), self.s_context(hartid)].iter().flatten() {
+            self.set_threshold(*context, 0);
+            // 只有使能的中断源的完成才有效
+            self.set_all_enables(*context, true);
+            for source in 1..=self.ndev() {
+                self.write(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM, source as u32);
+            }
+            self.set_all_enables(*context, false);
+        }
+    }
+
+    /// 在当前硬件线程的 M 态上下文中打开中断源 `source` ，中断到来时调用 `handler`
+    pub fn enable(&self, source: u32, handler: fn()) -> bool {
+        let context = match self.m_context(mhartid::read()) {
+            Some(context) => context,
+            None => return false,
+        };
+        if source == 0 || source as usize > self.ndev() {
+            return false;
+        }
+        {
+            let mut handlers = HANDLERS.lock();
+            // 同一个中断源重复注册时替换原来的处理函数
+            let slot = handlers
+                .iter()
+                .position(|slot| matches!(slot, Some((s, _)) if *s == source))
+                .or_else(|| handlers.iter().position(|slot| slot.is_none()));
+            match slot {
+                Some(index) => handlers[index] = Some((source, handler)),
+                None => return false,
+            }
+        }
+        self.set_priority(source, 1);
+        self.set_enable(context, source, true);
+        unsafe { mie::set_mext() };
+        true
+    }
+
+    /// M 态外部中断处理：认领并处理全部待处理的中断；没有注册处理函数的中断源会被关闭
+    pub fn handle(&self) {
+        let context = match self.m_context(mhartid::read()) {
+            Some(context) => context,
+            None => return,
+        };
+        let claim = CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM;
+        loop {
+            let source = self.read(claim);
+            if source == 0 {
+                break;
+            }
+            let handler = HANDLERS
+                .lock()
+                .iter()
+                .flatten()
+                .find(|&&(s, _)| s == source)
+                .map(|&(_, handler)| handler);
+            match handler {
+                Some(handler) => handler(),
+                None => {
+                    println!("[rustsbi-plic] unexpected interrupt {}, disabled", source);
+                    self.set_enable(context, source, false);
+                }
+            }
+            self.write(claim, source);
+        }
+    }
+
+    fn m_context(&self, hartid: usize) -> Option<usize> {
+        match self.m_context.get(hartid)?.load(Ordering::Relaxed) {
+            NO_CONTEXT => None,
+            context => Some(context),
+        }
+    }
+
+    fn s_context(&self, hartid: usize) -> Option<usize> {
+        match self.s_context.get(hartid)?.load(Ordering::Relaxed) {
+            NO_CONTEXT => None,
+            context => Some(context),
+        }
+    }
+
+    fn set_priority(&self, source: u32, priority: u32) {
+        self.write(PRIORITY_BASE + source as usize * 4, priority);
+    }
+
+    fn set_threshold(&self, context: usize, threshold: u32) {
+        self.write(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_THRESHOLD, threshold);
+    }
+
+    fn set_enable(&self, context: usize, source: u32, enable: bool) {
+        let offset = ENABLE_BASE + context * ENABLE_STRIDE + (source as usize / 32) * 4;
+        let bit = 1 << (source % 32);
+        let old = self.read(offset);
+        self.write(offset, if enable { old | bit } else { old & !bit });
+    }
+
+    fn set_all_enables(&self, context: usize, enable: bool) {
+        // 0 号中断源不存在，写 1 也没有影响
+        for word in 0..=self.ndev() / 32 {
+            let offset = ENABLE_BASE + context * ENABLE_STRIDE + word * 4;
+            self.write(offset, if enable { u32::MAX } else { 0 });
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base() + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base() + offset) as *mut u32, value) }
+    }
+}
@@ -0,0 +1,232 @@
+// 按设备树 compatible 选择的控制台串口
+use core::convert::Infallible;
+use core::fmt;
+use embedded_hal::serial::{Read, Write};
+use spin::{Mutex, MutexGuard};
+
+use device_tree::DeviceTree;
+
+use super::{Ns16550, SifiveUart, Uartlite};
+use crate::dtb;
+
+const DEFAULT_BAUD_RATE: u32 = 115200;
+
+// 固件自己使用的控制台串口，DBCN 和挂起唤醒都通过它访问
+static CONSOLE: Mutex<Option<Serial>> = Mutex::new(None);
+
+pub fn init_console(serial: Serial) {
+    *CONSOLE.lock() = Some(serial);
+}
+
+/// 控制台串口；还没有初始化时为 None
+pub fn console() -> MutexGuard<'static, Option<Serial>> {
+    CONSOLE.lock()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// 接收 FIFO 或接收缓冲区溢出，有数据丢失
+    Overrun,
+    /// 帧错误
+    Frame,
+    /// 校验错误
+    Parity,
+}
+
+#[derive(Clone)]
+pub enum Serial {
+    Uartlite(Uartlite),
+    SifiveUart(SifiveUart),
+    Ns16550(Ns16550),
+}
+
+impl Serial {
+    /// 按 `/chosen` 的 `stdout-path` 选择串口，没有指定时用第一个认识的串口；都找不到时返回 None。
+    /// 驱动按节点的 compatible 选择；打开 `uartlite_console` 特性时不看 compatible ，都当作 Uartlite
+    pub unsafe fn probe(dtb_pa: usize) -> Option<Serial> {
+        let dt = dtb::load(dtb_pa)?;
+        let device = find_device(&dt)?;
+        let (base, _) = device.reg(0)?;
+        let clock_frequency = device.prop_u32("clock-frequency").filter(|&f| f != 0);
+        let baud_rate = device
+            .prop_u32("current-speed")
+            .unwrap_or(DEFAULT_BAUD_RATE);
+        // 旧版启动工具的设备树把 ZCU102 上的 AXI Uartlite 写成了 sifive,uart0 ，
+        // 用这样的设备树启动时打开 uartlite_console ，不看 compatible
+        let by_compatible = !cfg!(feature = "uartlite_console");
+        let ns16550 = device.is_compatible("ns16550a") || device.is_compatible("ns16550");
+        let serial = if by_compatible && device.is_compatible("sifive,uart0") {
+            Serial::SifiveUart(SifiveUart::new(base, clock_frequency, baud_rate))
+        } else if by_compatible && ns16550 {
+            let shift = device.prop_u32("reg-shift").unwrap_or(0) as usize;
+            let io_width = device.prop_u32("reg-io-width").unwrap_or(1) as usize;
+            Serial::Ns16550(Ns16550::new(base, shift, io_width, clock_frequency, baud_rate))
+        } else {
+            // Uartlite 的波特率在比特流中固定，不需要设置
+            Serial::Uartlite(Uartlite::new(base, 0))
+        };
+        Some(serial)
+    }
+
+    /// `probe` 选中的串口节点的中断号（ `interrupts` 的第一项），没有时返回 None
+    pub unsafe fn interrupt(dtb_pa: usize) -> Option<u32> {
+        let dt = dtb::load(dtb_pa)?;
+        find_device(&dt).and_then(|device| device.prop_u32s("interrupts").next())
+    }
+
+    /// 是否收到了数据，不取出
+    pub fn rx_ready(&self) -> bool {
+        match self {
+            Serial::Uartlite(uart) => uart.rx_ready(),
+            Serial::SifiveUart(uart) => uart.rx_ready(),
+            Serial::Ns16550(uart) => uart.rx_ready(),
+        }
+    }
+
+    /// 打开串口中断，中断处理中调用 [`Uartlite::poll`]；目前只有 Uartlite 支持，其它串口返回 false
+    pub fn enable_interrupt(&self) -> bool {
+        match self {
+            Serial::Uartlite(uart) => {
+                uart.enable_interrupt();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// 按 `/chosen` 的 `stdout-path` 选择串口，没有指定时用第一个认识的串口
+fn find_device<'a>(dt: &'a DeviceTree) -> Option<dtb::Device<'a>> {
+    const COMPATIBLE: &[&str] = &[
+        "xlnx,xps-uartlite-1.00.a",
+        "xlnx,opb-uartlite-1.00.b",
+        "sifive,uart0",
+        "ns16550a",
+        "ns16550",
+    ];
+    let stdout = dt
+        .find("/chosen")
+        .and_then(|chosen| chosen.prop_str("stdout-path").ok())
+        // 去掉 "serial0:115200n8" 中的串口参数
+        .and_then(|path| dtb::find_path(dt, path.split(':').next().unwrap_or(path)))
+        .filter(|device| COMPATIBLE.iter().any(|c| device.is_compatible(c)));
+    match stdout {
+        Some(device) => Some(device),
+        None => dtb::find_compatible(dt, COMPATIBLE),
+    }
+}
+
+impl Read<u8> for Serial {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        match self {
+            Serial::Uartlite(uart) => uart.read(),
+            Serial::SifiveUart(uart) => uart.read().map_err(|e| e.map(|e| match e {})),
+            Serial::Ns16550(uart) => uart.read(),
+        }
+    }
+}
+
+impl Write<u8> for Serial {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        match self {
+            Serial::Uartlite(uart) => uart.write(word),
+            Serial::SifiveUart(uart) => uart.write(word),
+            Serial::Ns16550(uart) => uart.write(word),
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        match self {
+            Serial::Uartlite(uart) => uart.flush(),
+            Serial::SifiveUart(uart) => uart.flush(),
+            Serial::Ns16550(uart) => uart.flush(),
+        }
+    }
+}
+
+/// panic 时的控制台输出，不经过 rustsbi 的 legacy stdio ，也不等待可能被 panic 的代码持有的锁
+pub struct PanicConsole {
+    serial: Option<Serial>,
+}
+
+impl PanicConsole {
+    /// 接管控制台串口；CONSOLE 被锁住时强制解锁，panic 之后不会再有正常的输出
+    pub fn take() -> Self {
+        let serial = match CONSOLE.try_lock() {
+            Some(console) => console.clone(),
+            None => unsafe {
+                CONSOLE.force_unlock();
+                CONSOLE.try_lock().and_then(|console| console.clone())
+            },
+        };
+        if let Some(Serial::Uartlite(uart)) = &serial {
+            uart.take_over();
+        }
+        Self { serial }
+    }
+}
+
+impl fmt::Write for PanicConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &word in s.as_bytes() {
+            match self.serial.as_mut() {
+                Some(Serial::Uartlite(uart)) => {
+                    if word == b'\n' {
+                        uart.write_direct(b'\r');
+                    }
+                    uart.write_direct(word);
+                }
+                // 其它串口的驱动本来就不加锁
+                Some(serial) => {
+                    let _ = nb::block!(serial.write(word));
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 给 legacy 控制台用的串口
+///
+/// rustsbi 的 getchar 遇到读错误会 panic，每次 putchar 之后都会等待 flush；
+/// 这里丢弃读错误，flush 也不等待，Uartlite 发送缓冲区中的数据在之后的陷入中发出
+pub struct SerialStdio {
+    inner: Serial,
+}
+
+impl SerialStdio {
+    pub fn new(inner: Serial) -> Self {
+        Self { inner }
+    }
+}
+
+impl Read<u8> for SerialStdio {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        loop {
+            match self.inner.read() {
+                Ok(word) => return Ok(word),
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(_)) => continue,
+            }
+        }
+    }
+}
+
+impl Write<u8> for SerialStdio {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.inner.write(word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+// SiFive UART，sifive,uart0
+// Ref: SiFive FU540-C000 Manual, Chapter 13
+use core::convert::Infallible;
+use core::ptr::{read_volatile, write_volatile};
+use embedded_hal::serial::{Read, Write};
+
+#[derive(Clone)]
+pub struct SifiveUart {
+    base: usize,
+}
+
+impl SifiveUart {
+    /// `clock_frequency` 为 None 时保留原来的分频设置
+    pub fn new(base: usize, clock_frequency: Option<u32>, baud_rate: u32) -> Self {
+        let uart = Self { base };
+        if let Some(clock_frequency) = clock_frequency {
+            // 波特率 = 时钟频率 / (div + 1)
+            let div = (clock_frequency / baud_rate).saturating_sub(1);
+            uart.write_reg(offsets::DIV, div);
+        }
+        // 发送 FIFO 中少于一项（即已经为空）时置位 txwm，用于 flush
+        uart.write_reg(offsets::TXCTRL, masks::TXEN | (1 << masks::TXCNT_SHIFT));
+        uart.write_reg(offsets::RXCTRL, masks::RXEN);
+        uart.write_reg(offsets::IE, 0);
+        uart
+    }
+
+    /// 接收 FIFO 中是否有数据，不取出
+    pub fn rx_ready(&self) -> bool {
+        // rxcnt 为 0，接收 FIFO 中多于零项时置位 rxwm，不论中断是否打开
+        self.read_reg(offsets::IP) & masks::RXWM != 0
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn write_byte(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        if self.read_reg(offsets::TXDATA) & masks::FULL != 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.write_reg(offsets::TXDATA, word as u32);
+        Ok(())
+    }
+}
+
+impl Read<u8> for SifiveUart {
+    // 这个串口没有错误状态
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let rxdata = self.read_reg(offsets::RXDATA);
+        if rxdata & masks::EMPTY != 0 {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(rxdata as u8)
+        }
+    }
+}
+
+impl Write<u8> for SifiveUart {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        if word == b'\n' {
+            nb::block!(self.write_byte(b'\r'))?;
+        }
+        self.write_byte(word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        if self.read_reg(offsets::IP) & masks::TXWM != 0 {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+mod offsets {
+    pub const TXDATA: usize = 0x00;
+    pub const RXDATA: usize = 0x04;
+    pub const TXCTRL: usize = 0x08;
+    pub const RXCTRL: usize = 0x0c;
+    pub const IE: usize = 0x10;
+    pub const IP: usize = 0x14;
+    pub const DIV: usize = 0x18;
+}
+
+mod masks {
+    // txdata/rxdata
+    pub const FULL: u32 = 1 << 31;
+    pub const EMPTY: u32 = 1 << 31;
+    // txctrl/rxctrl
+    pub const TXEN: u32 = 1 << 0;
+    pub const RXEN: u32 = 1 << 0;
+    pub const TXCNT_SHIFT: u32 = 16;
+    // ip
+    pub const TXWM: u32 = 1 << 0;
+    pub const RXWM: u32 = 1 << 1;
+}
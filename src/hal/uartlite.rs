@@ -1,7 +1,71 @@
+use core::arch::asm;
 use core::convert::Infallible;
 use core::ptr::{read_volatile, write_volatile};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use embedded_hal::serial::{Read, Write};
+use spin::Mutex;
 
+use super::ring_buffer::RingBuffer;
+use super::serial::Error;
+
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 4096;
+
+/// 各类错误发生的次数
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorCounts {
+    /// 接收 FIFO 溢出
+    pub overrun: usize,
+    pub frame: usize,
+    pub parity: usize,
+    /// 接收缓冲区满，丢弃的字节数
+    pub dropped: usize,
+}
+
+// 板上只有一个串口，收发缓冲区也只有一份，所有 Uartlite 句柄共用
+static BUFFERS: Mutex<Buffers> = Mutex::new(Buffers::new());
+
+// trap.S 中 DBCN write_byte 的快速路径使用：发送 FIFO 和状态寄存器的地址。
+// 只在发送缓冲区为空时给出发送 FIFO 的地址，快速路径写进 FIFO 的字节不会排到缓冲区中已有数据的前面；
+// 只在持有 TX_OWNER 时修改
+#[export_name = "uartlite_fast_tx"]
+static FAST_TX: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+// 发送 FIFO 的所有权。快速路径不拿 BUFFERS 的锁，它和 `service` 都要先用 amoswap 把这里置 1 ，
+// 才能检查 FIFO 是否已满并写入，否则两个硬件线程可能同时看到 FIFO 没满，多写的字节会被丢掉
+#[export_name = "uartlite_tx_owner"]
+static TX_OWNER: AtomicU32 = AtomicU32::new(0);
+
+// panic 时等待发送 FIFO 所有权的次数，超过后直接占用
+const TAKE_OVER_SPINS: usize = 1_000_000;
+
+// 持有发送 FIFO 的所有权，离开作用域时释放；快速路径只持有几条指令的时间，这里直接自旋。
+// 原子操作的 Acquire 和 Release 不约束设备访问，和 trap.S 一样另加包含 I/O 的 fence
+struct TxOwner;
+
+impl TxOwner {
+    fn acquire() -> Self {
+        while TX_OWNER.swap(1, Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+        unsafe { asm!("fence r, iorw") };
+        TxOwner
+    }
+}
+
+impl Drop for TxOwner {
+    fn drop(&mut self) {
+        unsafe { asm!("fence iorw, w") };
+        TX_OWNER.store(0, Ordering::Release);
+    }
+}
+
+// 快速路径读状态寄存器时看到的错误位，下一次 `service` 时计数
+#[export_name = "uartlite_pending_stat"]
+static PENDING_STAT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone)]
 pub struct Uartlite {
     base: usize,
     shift: usize,
@@ -9,69 +73,127 @@ pub struct Uartlite {
 
 impl Uartlite {
     pub fn new(base: usize, shift: usize) -> Self {
+        let regs = Registers { base, shift };
         // init process; ref: MeowSBI/utils/uart.rs
-        unsafe {
-            write_volatile(
-                (base + (offsets::CTRL_REG << shift)) as *mut u8,
-                masks::RST_FIFO as u8,
-            );
-        }
+        regs.write(offsets::CTRL_REG, masks::RST_FIFO);
         // init finished
+        BUFFERS.lock().regs = Some(regs);
         Self { base, shift }
     }
+
+    /// 打开串口中断：接收 FIFO 有数据或发送 FIFO 变空时产生中断，中断处理中调用 [`Uartlite::poll`]
+    pub fn enable_interrupt(&self) {
+        let regs = Registers {
+            base: self.base,
+            shift: self.shift,
+        };
+        regs.write(offsets::CTRL_REG, masks::INTR_EN);
+    }
+
+    /// 在收发缓冲区和硬件 FIFO 之间搬运数据；在每次陷入时调用
+    ///
+    /// 其它硬件线程正在使用串口时直接返回，不会等待
+    pub fn poll() {
+        if let Some(mut buffers) = BUFFERS.try_lock() {
+            buffers.service();
+        }
+    }
+
+    /// 接收缓冲区中是否有数据，不取出
+    pub fn rx_ready(&self) -> bool {
+        let mut buffers = BUFFERS.lock();
+        buffers.service();
+        !buffers.rx.is_empty()
+    }
+
+    /// 启动以来各类错误的次数
+    #[allow(dead_code)]
+    pub fn error_counts() -> ErrorCounts {
+        BUFFERS.lock().counts
+    }
+
+    /// panic 时接管发送 FIFO ，之后用 [`Uartlite::write_direct`] 输出。
+    ///
+    /// 不等待 BUFFERS 的锁，panic 的代码可能正持有它：能拿到时先把缓冲区中的数据发完并一直锁住，
+    /// 拿不到时丢掉缓冲区中的数据。发送 FIFO 的所有权等一会儿还拿不到就直接占用，之后不再释放，
+    /// 其它硬件线程的快速路径都退回完整的处理
+    pub fn take_over(&self) {
+        for _ in 0..TAKE_OVER_SPINS {
+            if TX_OWNER.swap(1, Ordering::Acquire) == 0 {
+                break;
+            }
+            spin_loop();
+        }
+        TX_OWNER.store(1, Ordering::Relaxed);
+        if let Some(mut buffers) = BUFFERS.try_lock() {
+            while let Some(word) = buffers.tx.pop() {
+                self.write_direct(word);
+            }
+            core::mem::forget(buffers);
+        }
+    }
+
+    /// 不经过发送缓冲区，等发送 FIFO 有空位后直接写入；只在 `take_over` 之后使用
+    pub fn write_direct(&self, word: u8) {
+        let regs = Registers {
+            base: self.base,
+            shift: self.shift,
+        };
+        while regs.read(offsets::STAT_REG) & masks::TX_FULL != 0 {
+            spin_loop();
+        }
+        regs.write(offsets::TX_FIFO, word);
+    }
+
+    /// 等待发送缓冲区中的数据全部写进发送 FIFO
+    pub fn drain() {
+        let mut buffers = BUFFERS.lock();
+        while buffers.regs.is_some() && !buffers.tx.is_empty() {
+            buffers.service();
+        }
+    }
 }
 
 impl Read<u8> for Uartlite {
-    // 其实是可能出错的，overrun啊，这些
-    type Error = Infallible;
+    type Error = Error;
 
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
-        let pending =
-            unsafe { read_volatile((self.base + (offsets::STAT_REG << self.shift)) as *const u8) }
-                & masks::RX_VALID;
-        if pending != 0 {
-            let word = unsafe {
-                read_volatile((self.base + (offsets::RX_FIFO << self.shift)) as *const u8)
-            };
-            Ok(word)
-        } else {
-            Err(nb::Error::WouldBlock)
+        let mut buffers = BUFFERS.lock();
+        buffers.service();
+        if let Some(e) = buffers.error.take() {
+            return Err(nb::Error::Other(e));
         }
+        buffers.rx.pop().ok_or(nb::Error::WouldBlock)
     }
 }
 
 impl Write<u8> for Uartlite {
     type Error = Infallible;
 
+    // 只写进发送缓冲区，缓冲区满时才需要等待
     fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
-        if word == ('\n' as u8) {
-            if let Err(e) = self.write('\r' as u8) {
-                return Err(e);
+        let mut buffers = BUFFERS.lock();
+        let needed = if word == b'\n' { 2 } else { 1 };
+        if buffers.tx.free() < needed {
+            buffers.service();
+            if buffers.tx.free() < needed {
+                return Err(nb::Error::WouldBlock);
             }
         }
-        // 写，但是不刷新
-        unsafe {
-            let mut pending =
-                read_volatile((self.base + (offsets::STAT_REG << self.shift)) as *const u8)
-                    & masks::TX_FULL;
-            while pending != 0 {
-                pending =
-                    read_volatile((self.base + (offsets::STAT_REG << self.shift)) as *const u8)
-                        & masks::TX_FULL;
-            }
-            write_volatile(
-                (self.base + (offsets::TX_FIFO << self.shift)) as *mut u8,
-                word,
-            )
-        };
+        // 先关掉快速路径，之后的字节都排在缓冲区中的数据后面
+        buffers.close_fast_tx();
+        if word == b'\n' {
+            let _ = buffers.tx.push(b'\r');
+        }
+        let _ = buffers.tx.push(word);
+        buffers.service();
         Ok(())
     }
 
     fn flush(&mut self) -> nb::Result<(), Self::Error> {
-        let pending =
-            unsafe { read_volatile((self.base + (offsets::STAT_REG << self.shift)) as *const u8) }
-                & masks::TX_FULL;
-        if pending == 0 {
+        let mut buffers = BUFFERS.lock();
+        buffers.service();
+        if buffers.tx.is_empty() {
             // 发送已经结束了
             Ok(())
         } else {
@@ -81,6 +203,105 @@ impl Write<u8> for Uartlite {
     }
 }
 
+struct Buffers {
+    regs: Option<Registers>,
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    // 还没有报告给读者的错误
+    error: Option<Error>,
+    counts: ErrorCounts,
+}
+
+impl Buffers {
+    const fn new() -> Self {
+        Self {
+            regs: None,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            error: None,
+            counts: ErrorCounts {
+                overrun: 0,
+                frame: 0,
+                parity: 0,
+                dropped: 0,
+            },
+        }
+    }
+
+    // 要往发送缓冲区中放数据时调用，之后的 `service` 在缓冲区清空时重新打开
+    fn close_fast_tx(&mut self) {
+        let _owner = TxOwner::acquire();
+        FAST_TX[0].store(0, Ordering::Relaxed);
+    }
+
+    // 把接收 FIFO 中的数据搬进接收缓冲区，把发送缓冲区中的数据尽量写进发送 FIFO
+    fn service(&mut self) {
+        let regs = match self.regs {
+            Some(regs) => regs,
+            None => return,
+        };
+        let _owner = TxOwner::acquire();
+        loop {
+            // 错误位在读状态寄存器后自动清零，每次读都要记下来
+            let stat = regs.read(offsets::STAT_REG) | PENDING_STAT.swap(0, Ordering::Relaxed) as u8;
+            // 同时出现多个错误时都计数，只报告最严重的一个
+            if stat & masks::PARITY_ERROR != 0 {
+                self.counts.parity += 1;
+                self.error = Some(Error::Parity);
+            }
+            if stat & masks::FRAME_ERROR != 0 {
+                self.counts.frame += 1;
+                self.error = Some(Error::Frame);
+            }
+            if stat & masks::OVERRUN_ERROR != 0 {
+                self.counts.overrun += 1;
+                self.error = Some(Error::Overrun);
+            }
+            let mut progress = false;
+            if stat & masks::RX_VALID != 0 {
+                let word = regs.read(offsets::RX_FIFO);
+                if self.rx.push(word).is_err() {
+                    self.counts.dropped += 1;
+                    self.error = Some(Error::Overrun);
+                }
+                progress = true;
+            }
+            if stat & masks::TX_FULL == 0 {
+                if let Some(word) = self.tx.pop() {
+                    regs.write(offsets::TX_FIFO, word);
+                    progress = true;
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        let fast_tx = if self.tx.is_empty() { regs.addr(offsets::TX_FIFO) } else { 0 };
+        FAST_TX[1].store(regs.addr(offsets::STAT_REG), Ordering::Relaxed);
+        FAST_TX[0].store(fast_tx, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Registers {
+    base: usize,
+    shift: usize,
+}
+
+impl Registers {
+    fn addr(&self, offset: usize) -> usize {
+        self.base + (offset << self.shift)
+    }
+
+    fn read(&self, offset: usize) -> u8 {
+        unsafe { read_volatile(self.addr(offset) as *const u8) }
+    }
+
+    fn write(&self, offset: usize, value: u8) {
+        unsafe { write_volatile(self.addr(offset) as *mut u8, value) }
+    }
+}
+
 mod offsets {
     pub const RX_FIFO: usize = 0x0;
     pub const TX_FIFO: usize = 0x4;
@@ -89,8 +310,13 @@ mod offsets {
 }
 
 mod masks {
+    // 控制寄存器
     pub const RST_FIFO: u8 = 0x03;
-    // pub const INTR_EN: u8 = 0x10;
+    pub const INTR_EN: u8 = 0x10;
+    // 状态寄存器
+    pub const PARITY_ERROR: u8 = 0x80;
+    pub const FRAME_ERROR: u8 = 0x40;
+    pub const OVERRUN_ERROR: u8 = 0x20;
     pub const TX_FULL: u8 = 0x08;
     // pub const TX_EMPTY: u8 = 0x04;
     // pub const RX_FULL: u8 = 0x02;
@@ -0,0 +1,343 @@
+// 每个硬件线程的上下文
+//
+// 进入 main 之后 mscratch 始终指向当前硬件线程的上下文，陷入入口用它找到 M 态栈，
+// 陷入处理中通过 `current()` 访问自己的状态，不需要加锁，也不需要按 mhartid 重新计算地址
+use core::arch::asm;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use riscv::register::mhartid;
+
+use crate::ecall::pmu::FIRMWARE_EVENTS;
+use crate::hal::hart_state_id;
+use crate::stats::{TrapStats, TRAP_STATS_INIT};
+use crate::MAX_HARTS;
+
+/// 跨核消息：发送方在对方的邮箱中置位，再发 M 态软件中断；接收方在中断处理中一次取走全部消息
+pub mod ipi {
+    /// 转发给 S 态的软件中断
+    pub const SSIP: usize = 1 << 0;
+}
+
+// 栈底的金丝雀和栈的涂色值，和 entry.S 一致
+const STACK_GUARD: usize = 256;
+const STACK_CANARY: usize = 0x5ca1_ab1e_dead_beef;
+const STACK_PAINT: usize = 0xa5a5_a5a5_a5a5_a5a5;
+
+/// 一个硬件线程 M 态栈的使用情况
+pub struct StackUsage {
+    /// 不含金丝雀的可用大小
+    pub size: usize,
+    /// 启动以来的最大用量
+    pub used: usize,
+    /// 金丝雀被改写过，栈已经溢出
+    pub overflowed: bool,
+}
+
+// 嵌套陷入使用的异常栈大小
+const EXCEPTION_STACK_SIZE: usize = 0x2000;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct ExceptionStack([u8; EXCEPTION_STACK_SIZE]);
+
+// 只通过 sp 使用，Rust 代码只取地址
+static mut EXCEPTION_STACKS: [ExceptionStack; MAX_HARTS] =
+    [ExceptionStack([0; EXCEPTION_STACK_SIZE]); MAX_HARTS];
+
+// hartid 之前的字段由 trap.S 按编号访问，顺序必须和 trap.S 中的 CTX_* 一致；
+// 除了邮箱以外只由对应的硬件线程写入，用原子类型只是为了能放在普通的 static 中
+#[repr(C)]
+pub struct HartContext {
+    // M 态栈顶，从 S/U 态陷入时从这里开始使用
+    stack_top: AtomicUsize,
+    // 陷入入口的暂存空间，保存被打断的 sp 和 tp
+    scratch_sp: AtomicUsize,
+    scratch_tp: AtomicUsize,
+    // 正在处理的陷入的层数，0 表示不在陷入处理中
+    depth: AtomicUsize,
+    // 最内层陷入的陷入帧
+    frame: AtomicUsize,
+    // 异常栈栈顶，第二层陷入从这里开始使用
+    exception_stack_top: AtomicUsize,
+    // 快速路径保存 t0 到 t3 的地方
+    fast_scratch: [AtomicUsize; 4],
+    // 快速路径保存被打断的 sp 和 tp 的地方，进入快速路径后 mscratch 马上恢复为上下文的地址
+    fast_sp: AtomicUsize,
+    fast_tp: AtomicUsize,
+    // 本硬件线程 msip 寄存器的地址，0 表示还不知道，软件中断走完整的处理
+    msip: AtomicUsize,
+    // 其它硬件线程发来、还没有处理的消息
+    ipi: AtomicUsize,
+    // 非 0 时常用的 SBI 调用走 trap.S 中的快速路径
+    fast_ecall: AtomicUsize,
+    // mtime 和本硬件线程 mtimecmp 的地址，0 表示 set_timer 走完整的处理，比如使用 Sstc 时
+    mtime: AtomicUsize,
+    mtimecmp: AtomicUsize,
+    // 基准测试期间保存 M 态现场的栈指针
+    bench_sp: AtomicUsize,
+    fw_events: [AtomicUsize; FIRMWARE_EVENTS],
+    stats: TrapStats,
+    hartid: AtomicUsize,
+    hsm_state: AtomicU8,
+    // hart_start 给出的入口和 opaque ，start_pending 置位之后才有效
+    start_addr: AtomicUsize,
+    start_opaque: AtomicUsize,
+    start_pending: AtomicBool,
+    // 栈底；基准测试期间栈顶会临时下移，检查金丝雀只依赖栈底
+    stack_bottom: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const CONTEXT_INIT: HartContext = HartContext {
+    stack_top: ZERO,
+    scratch_sp: ZERO,
+    scratch_tp: ZERO,
+    depth: ZERO,
+    frame: ZERO,
+    exception_stack_top: ZERO,
+    fast_scratch: [ZERO; 4],
+    fast_sp: ZERO,
+    fast_tp: ZERO,
+    msip: ZERO,
+    ipi: ZERO,
+    fast_ecall: ZERO,
+    mtime: ZERO,
+    mtimecmp: ZERO,
+    bench_sp: ZERO,
+    fw_events: [ZERO; FIRMWARE_EVENTS],
+    stats: TRAP_STATS_INIT,
+    hartid: ZERO,
+    hsm_state: AtomicU8::new(hart_state_id::STOPPED),
+    start_addr: ZERO,
+    start_opaque: ZERO,
+    start_pending: AtomicBool::new(false),
+    stack_bottom: ZERO,
+};
+
+static CONTEXTS: [HartContext; MAX_HARTS] = [CONTEXT_INIT; MAX_HARTS];
+
+// send_ipi 快速路径按目标查的表：每项是邮箱的地址和触发寄存器的地址。
+// 有 SSWI 时邮箱为 0 ，直接写对方的 setssip ；触发寄存器为 0 的目标走完整的处理
+#[allow(clippy::declare_interior_mutable_const)]
+const IPI_TARGET_INIT: [AtomicUsize; 2] = [ZERO; 2];
+#[export_name = "ipi_targets"]
+static IPI_TARGETS: [[AtomicUsize; 2]; MAX_HARTS] = [IPI_TARGET_INIT; MAX_HARTS];
+
+// 最大的硬件线程编号；usize::MAX 表示还没有读取设备树，使用链接脚本中的值
+static MAX_HART_ID: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// 初始化当前硬件线程的上下文并让 mscratch 指向它，必须是 main 中做的第一件事。
+///
+/// entry.S 把算好的栈顶暂存在 mscratch 中
+pub fn init() {
+    let hartid = mhartid::read();
+    let stack_top: usize;
+    let context = &CONTEXTS[hartid];
+    unsafe { asm!("csrr {0}, mscratch", out(reg) stack_top) };
+    context.stack_top.store(stack_top, Ordering::Relaxed);
+    context
+        .stack_bottom
+        .store(stack_top - hart_stack_size(), Ordering::Relaxed);
+    let exception_stack = unsafe { core::ptr::addr_of!(EXCEPTION_STACKS[hartid]) } as usize;
+    context
+        .exception_stack_top
+        .store(exception_stack + EXCEPTION_STACK_SIZE, Ordering::Relaxed);
+    context.hartid.store(hartid, Ordering::Relaxed);
+    unsafe { asm!("csrw mscratch, {0}", in(reg) context as *const HartContext) };
+}
+
+/// 当前硬件线程的上下文
+pub fn current() -> &'static HartContext {
+    unsafe {
+        let context: *const HartContext;
+        asm!("csrr {0}, mscratch", out(reg) context);
+        &*context
+    }
+}
+
+/// 编号为 `hartid` 的硬件线程的上下文，超出静态分配的数量时为 None
+pub fn context(hartid: usize) -> Option<&'static HartContext> {
+    CONTEXTS.get(hartid)
+}
+
+/// 设置 send_ipi 快速路径中 `hartid` 的目标：`trigger` 为 msip 或 setssip 的地址，
+/// `mailbox` 表示是否先在对方的邮箱中留言
+pub fn set_ipi_target(hartid: usize, trigger: usize, mailbox: bool) {
+    let (target, context) = match (IPI_TARGETS.get(hartid), context(hartid)) {
+        (Some(target), Some(context)) => (target, context),
+        _ => return,
+    };
+    let mailbox = if mailbox { &context.ipi as *const AtomicUsize as usize } else { 0 };
+    target[0].store(mailbox, Ordering::Relaxed);
+    target[1].store(trigger, Ordering::Release);
+}
+
+pub fn max_hart_id() -> usize {
+    match MAX_HART_ID.load(Ordering::Relaxed) {
+        usize::MAX => compiled_max_hart_id(),
+        max_hart_id => max_hart_id,
+    }
+}
+
+pub fn set_max_hart_id(max_hart_id: usize) {
+    MAX_HART_ID.store(max_hart_id, Ordering::Relaxed);
+}
+
+// 每个硬件线程栈的大小，和 _max_hart_id 一样是绝对符号
+fn hart_stack_size() -> usize {
+    let ans;
+    unsafe {
+        asm!("
+        lui     {ans}, %hi(_hart_stack_size)
+        add     {ans}, {ans}, %lo(_hart_stack_size)
+    ", ans = out(reg) ans)
+    };
+    ans
+}
+
+// 链接脚本中的 _max_hart_id 是绝对符号，离代码太远，不能用 PC 相对寻址
+#[inline]
+pub fn compiled_max_hart_id() -> usize {
+    let ans;
+    unsafe {
+        asm!("
+        lui     {ans}, %hi(_max_hart_id)
+        add     {ans}, {ans}, %lo(_max_hart_id)
+    ", ans = out(reg) ans)
+    };
+    ans
+}
+
+impl HartContext {
+    pub fn hartid(&self) -> usize {
+        self.hartid.load(Ordering::Relaxed)
+    }
+
+    #[cfg_attr(not(feature = "monitor"), allow(dead_code))]
+    pub fn stack_top(&self) -> usize {
+        self.stack_top.load(Ordering::Relaxed)
+    }
+
+    /// 是否已经调用过 `init`
+    pub fn started(&self) -> bool {
+        self.stack_bottom.load(Ordering::Relaxed) != 0
+    }
+
+    /// 栈底金丝雀的最高一个字，栈溢出时最先被改写；每次陷入都检查，开销很小
+    pub fn stack_guard_intact(&self) -> bool {
+        let guard_top = self.stack_bottom.load(Ordering::Relaxed) + STACK_GUARD - 8;
+        unsafe { read_volatile(guard_top as *const usize) == STACK_CANARY }
+    }
+
+    /// 按涂色值统计栈的最大用量；硬件线程没有启动过时为 None
+    pub fn stack_usage(&self) -> Option<StackUsage> {
+        let bottom = match self.stack_bottom.load(Ordering::Relaxed) {
+            0 => return None,
+            bottom => bottom,
+        };
+        let top = bottom + hart_stack_size();
+        let overflowed = (bottom..bottom + STACK_GUARD)
+            .step_by(8)
+            .any(|addr| unsafe { read_volatile(addr as *const usize) } != STACK_CANARY);
+        // 从金丝雀往上找第一个被用过的字
+        let first_used = (bottom + STACK_GUARD..top)
+            .step_by(8)
+            .find(|&addr| unsafe { read_volatile(addr as *const usize) } != STACK_PAINT)
+            .unwrap_or(top);
+        Some(StackUsage {
+            size: top - bottom - STACK_GUARD,
+            used: top - first_used,
+            overflowed,
+        })
+    }
+
+    /// 正在处理的陷入的层数，在陷入处理中至少为 1
+    pub fn trap_depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// 设置本硬件线程 msip 寄存器的地址，之后 M 态软件中断走快速路径
+    pub fn set_msip(&self, msip: usize) {
+        self.msip.store(msip, Ordering::Relaxed);
+    }
+
+    /// 设置 mtime 和本硬件线程 mtimecmp 的地址，之后 set_timer 走快速路径
+    pub fn set_fast_timer(&self, mtime: usize, mtimecmp: usize) {
+        self.mtime.store(mtime, Ordering::Relaxed);
+        self.mtimecmp.store(mtimecmp, Ordering::Relaxed);
+    }
+
+    /// 打开或关闭常用 SBI 调用的快速路径，关闭后全部走完整的保存和 Rust 处理
+    pub fn set_fast_ecall(&self, enable: bool) {
+        self.fast_ecall.store(enable as usize, Ordering::Relaxed);
+    }
+
+    /// 给这个硬件线程留下消息，之后由调用者发 M 态软件中断
+    pub fn post_ipi(&self, message: usize) {
+        self.ipi.fetch_or(message, Ordering::Release);
+    }
+
+    /// 取走全部消息
+    pub fn take_ipi(&self) -> usize {
+        self.ipi.swap(0, Ordering::Acquire)
+    }
+
+    pub fn hsm_state(&self) -> &AtomicU8 {
+        &self.hsm_state
+    }
+
+    /// 记录 hart_start 给出的入口和 opaque ，之后由调用者发 M 态软件中断唤醒这个硬件线程
+    pub fn request_start(&self, start_addr: usize, opaque: usize) {
+        self.set_start_params(start_addr, opaque);
+        self.start_pending.store(true, Ordering::Release);
+    }
+
+    /// 直接设置进入 S 态时的入口和 a1 ，只由本硬件线程在没有启动请求时调用
+    pub fn set_start_params(&self, start_addr: usize, opaque: usize) {
+        self.start_addr.store(start_addr, Ordering::Relaxed);
+        self.start_opaque.store(opaque, Ordering::Relaxed);
+    }
+
+    /// 取走启动请求，没有请求时返回 None
+    pub fn take_start_request(&self) -> Option<(usize, usize)> {
+        if self.start_pending.swap(false, Ordering::Acquire) {
+            Some(self.start_params())
+        } else {
+            None
+        }
+    }
+
+    /// 最近一次启动请求的入口和 opaque
+    pub fn start_params(&self) -> (usize, usize) {
+        (
+            self.start_addr.load(Ordering::Relaxed),
+            self.start_opaque.load(Ordering::Relaxed),
+        )
+    }
+
+    /// 各类固件事件发生的次数，只由本硬件线程增加
+    pub fn fw_events(&self) -> &[AtomicUsize; FIRMWARE_EVENTS] {
+        &self.fw_events
+    }
+
+    /// 陷入和 SBI 调用的统计
+    pub fn stats(&self) -> &TrapStats {
+        &self.stats
+    }
+}
+
+/// 进入 S 态：a0 为 hartid，a1 为设备树地址或 hart_start 的 opaque ，mepc 和 mstatus 需要事先设置好。
+///
+/// mscratch 仍然指向上下文，之后从 S 态陷入时从栈顶重新使用 M 态栈，当前的栈帧不再需要
+pub unsafe fn enter_supervisor(hartid: usize, dtb_pa: usize) -> ! {
+    asm!(
+        "li sp, 0",
+        "mret",
+        in("a0") hartid,
+        in("a1") dtb_pa,
+        options(noreturn)
+    )
+}
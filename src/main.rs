@@ -1,22 +1,34 @@
 #![no_std]
 #![no_main]
-#![feature(naked_functions)]
 #![feature(alloc_error_handler)]
 
+extern crate alloc;
+
+#[cfg(feature = "ecall_bench")]
+mod bench;
+mod dtb;
+mod ecall;
 mod hal;
+mod hart;
 mod misaligned;
+#[cfg(feature = "monitor")]
+mod monitor;
+mod pmp;
+mod policy;
+mod stats;
 mod trap;
 
 #[cfg(not(test))]
 use core::alloc::Layout;
 use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(not(test))]
 use core::panic::PanicInfo;
 use linked_list_allocator::LockedHeap;
 
 use rustsbi::{print, println};
 
-use riscv::register::{medeleg, mhartid, mideleg, mie, mip};
+use riscv::register::{medeleg, mhartid, mideleg};
 
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
@@ -26,10 +38,22 @@ global_asm!(include_str!("entry.S"));
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
     let hart_id = mhartid::read();
+    // panic 可能发生在持有串口锁的时候，不用 println
+    let mut console = hal::PanicConsole::take();
     // 输出的信息大概是“[rustsbi-panic] hart 0 panicked at ...”
-    println!("[rustsbi-panic] hart {} {}", hart_id, info);
-    println!("[rustsbi-panic] system shutdown scheduled due to RustSBI panic");
+    let _ = writeln!(console, "[rustsbi-panic] hart {} {}", hart_id, info);
+    if let Some(usage) = hart::current().stack_usage() {
+        let _ = writeln!(
+            console,
+            "[rustsbi-panic] stack used {} of {} bytes{}",
+            usage.used,
+            usage.size,
+            if usage.overflowed { ", overflowed" } else { "" }
+        );
+    }
+    let _ = writeln!(console, "[rustsbi-panic] system shutdown scheduled due to RustSBI panic");
     // use rustsbi::Reset;
     // hal::Reset.system_reset(
     //     rustsbi::reset::RESET_TYPE_SHUTDOWN,
@@ -44,43 +68,39 @@ fn oom(_layout: Layout) -> ! {
     loop {}
 }
 
-lazy_static::lazy_static! {
-    // 最大的硬件线程编号；只在启动时写入，跨核软中断发生时读取
-    pub static ref MAX_HART_ID: spin::Mutex<usize> =
-        spin::Mutex::new(compiled_max_hartid());
-}
+// 默认的内核入口
+const KERNEL_ENTRY: usize = 0x1_0020_0000;
+// 与 DTS 中 timebase-frequency 一致
+const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+// 实际使用的内核入口，监控程序可以修改；hart 0 从这里进入 S 态
+static BOOT_ENTRY: AtomicUsize = AtomicUsize::new(KERNEL_ENTRY);
+
+// 每个硬件线程的静态状态按这个数量分配，不能小于链接脚本中的 _max_hart_id + 1
+pub const MAX_HARTS: usize = 8;
 
 // #[export_name = "_mp_hook"]
 pub extern "C" fn mp_hook() -> bool {
-    let hartid = mhartid::read();
-    if hartid == 0 {
+    if mhartid::read() == 0 {
         true
     } else {
-        use hal::Clint;
-        use riscv::asm::wfi;
-        unsafe {
-            let mut clint = Clint::new(0x200_0000 as *mut u8);
-            // Clear IPI
-            clint.clear_soft(hartid);
-            // Start listening for software interrupts
-            mie::set_msoft();
-
-            loop {
-                wfi();
-                if mip::read().msoft() {
-                    break;
-                }
-            }
-
-            // Stop listening for software interrupts
-            mie::clear_msoft();
-            // Clear IPI
-            clint.clear_soft(hartid);
+        // 等待其它硬件线程用 HART start 给出入口；不使用 HSM 的内核直接发 IPI ，
+        // 这时和 hart 0 一样带着设备树从默认的内核入口启动
+        if hal::HartStateManager::wait_for_start(true).is_none() {
+            hart::current().set_start_params(BOOT_ENTRY.load(Ordering::Acquire), external_dtb_pa());
         }
         false
     }
 }
 
+// entry.S 中内嵌设备树的地址
+fn external_dtb_pa() -> usize {
+    extern "C" {
+        static external_dtb: usize;
+    }
+    unsafe { &external_dtb as *const _ as usize }
+}
+
 fn clear_bss() {
     extern "C" {
         fn _sbss();
@@ -94,9 +114,11 @@ extern "C" fn main(_mhartid: usize) -> ! {
     // dtb_pa is put into a1 register on qemu boot
     // Ref: https://github.com/qemu/qemu/blob/aeb07b5f6e69ce93afea71027325e3e7a22d2149/hw/riscv/boot.c#L243
 
+    hart::init();
     if mp_hook() {
         // init
     }
+    hal::HartStateManager::mark_started();
 
     /* setup trap */
 
@@ -106,9 +128,10 @@ extern "C" fn main(_mhartid: usize) -> ! {
     extern "C" {
         static mut _sheap: u8;
         static mut _eheap: u8;
-        static external_dtb: usize;
     }
-    let dtb_pa = unsafe { &external_dtb } as *const _ as usize;
+    let dtb_pa = external_dtb_pa();
+    #[cfg(any(feature = "serial_boot", feature = "monitor"))]
+    let mut serial = None;
     if mhartid::read() == 0 {
         clear_bss();
         let sheap = unsafe { &mut _sheap } as *mut _ as usize;
@@ -118,25 +141,48 @@ extern "C" fn main(_mhartid: usize) -> ! {
             ALLOCATOR.lock().init(sheap, heap_size);
         }
 
+        // 按设备树选择串口，设备树中没有时使用板上的 Uartlite
         // 其实这些参数不用提供，直接通过pac库生成
-        let serial = hal::Uartlite::new(0x60000000, 0);
+        let uart = unsafe { hal::Serial::probe(dtb_pa) }
+            .unwrap_or_else(|| hal::Serial::Uartlite(hal::Uartlite::new(0x60000000, 0)));
         // use through macro
         use rustsbi::legacy_stdio::init_legacy_stdio_embedded_hal;
-        init_legacy_stdio_embedded_hal(serial);
+        init_legacy_stdio_embedded_hal(hal::SerialStdio::new(uart.clone()));
+        hal::init_console(uart.clone());
+        // 保留一份给启动阶段的串口交互使用
+        #[cfg(any(feature = "serial_boot", feature = "monitor"))]
+        {
+            serial = Some(uart);
+        }
         println!("[rustsbi] ----****----****----****----****----****----****----");
         // println!("[rustsbi] Serial initialized.");
 
-        let clint = hal::Clint::new(0x2000000 as *mut u8);
+        unsafe {
+            hal::clint().probe(dtb_pa);
+            hal::Plic::probe(dtb_pa);
+            hal::bus_error().probe(dtb_pa);
+        }
+        // 控制平面要在 init_pmp 之前注册，PMP 按它的寄存器区域禁止 S 态访问
+        if let Some(control_plane) = unsafe { hal::control_plane::probe(dtb_pa) } {
+            println!(
+                "[rustsbi] Control plane: {} labels, {} LLC ways",
+                control_plane.num_labels(),
+                control_plane.cache_ways()
+            );
+            ecall::lrv::init_control_plane(control_plane);
+        }
         use rustsbi::init_ipi;
-        init_ipi(clint);
+        init_ipi(hal::clint());
         // println!("[rustsbi] IPI initialized.");
 
-        // todo: do not create two instances
-        let clint = hal::Clint::new(0x2000000 as *mut u8);
         use rustsbi::init_timer;
-        init_timer(clint);
-        let clint = hal::Clint::new(0x2000000 as *mut u8);
-        clint.set_timer(0, u64::MAX);
+        init_timer(hal::clint());
+        hal::clint().set_mtimecmp(0, u64::MAX);
+        if let Some(dt) = unsafe { dtb::load(dtb_pa) } {
+            if dtb::cpus_have_extension(&dt, "sstc") {
+                hal::Clint::enable_sstc();
+            }
+        }
         // println!("[rustsbi] Timer initialized.");
 
         use rustsbi::init_reset;
@@ -149,6 +195,15 @@ extern "C" fn main(_mhartid: usize) -> ! {
     }
 
     trap::delegate_trap();
+    ecall::pmu::init_hart();
+    hal::Clint::init_hart_timer();
+    // 设备地址已经确定，M 态软件中断和常用的 SBI 调用可以走快速路径
+    hal::clint().init_hart_fast_path();
+    hart::current().set_fast_ecall(true);
+    if let Some(plic) = hal::plic() {
+        plic.init_hart();
+    }
+    hal::bus_error().init_hart();
     if mhartid::read() == 0 {
         use riscv::register::misa::{self, MXL};
         println!("[rustsbi] RustSBI version {}", rustsbi::VERSION);
@@ -174,13 +229,54 @@ extern "C" fn main(_mhartid: usize) -> ! {
         }
         println!("[rustsbi] mideleg: {:#x}", mideleg::read().bits());
         println!("[rustsbi] medeleg: {:#x}", medeleg::read().bits());
-        let mut guard = MAX_HART_ID.lock();
-        *guard = unsafe { count_harts(dtb_pa) };
-        drop(guard);
-        println!("[rustsbi] Kernel entry: 0x100200000");
+        hal::clint().print_config();
+        if let Some(plic) = hal::plic() {
+            println!("[rustsbi] PLIC: {:#x}, {} sources", plic.base(), plic.ndev());
+            // 控制台接收由 0 号 HART 的 M 态外部中断处理
+            if let Some(irq) = unsafe { hal::Serial::interrupt(dtb_pa) } {
+                let enabled = match hal::console().as_ref() {
+                    Some(serial) => serial.enable_interrupt(),
+                    None => false,
+                };
+                if enabled && plic.enable(irq, hal::Uartlite::poll) {
+                    println!("[rustsbi] Console interrupt: {}", irq);
+                }
+            }
+            let units = hal::bus_error().units();
+            if units != 0 {
+                let irqs = hal::bus_error().enable_interrupts(plic);
+                println!("[rustsbi] Bus error units: {}, {} interrupts enabled", units, irqs);
+            }
+        }
+        if hal::Clint::sstc_enabled() {
+            println!("[rustsbi] Supervisor timer: Sstc stimecmp");
+        }
+        let pmp = pmp::probe();
+        if pmp.entries == 0 {
+            println!("[rustsbi] PMP: not implemented");
+        } else {
+            println!("[rustsbi] PMP: {} entries, granularity {} bytes", pmp.entries, pmp.granularity);
+        }
+        // 在内核运行之前划分 LLC 和访存带宽
+        if let Some(policy) = unsafe { policy::load(dtb_pa) } {
+            println!("[rustsbi] Partition policy: {}", policy.name);
+            if !ecall::lrv::apply_policy(&policy) {
+                println!("[rustsbi-policy] No control plane in the device tree, policy not applied");
+            }
+        }
+        hart::set_max_hart_id(unsafe { count_harts(dtb_pa) });
+        hal::clint().init_fast_ipi();
+        #[cfg(feature = "ecall_bench")]
+        bench::run();
+        println!("[rustsbi] Kernel entry: {:#x}", KERNEL_ENTRY);
+        #[cfg(any(feature = "serial_boot", feature = "monitor"))]
+        if let Some(serial) = serial.as_mut() {
+            boot_menu(serial, dtb_pa);
+        }
     }
 
-    init_pmp();
+    init_pmp(dtb_pa);
+    check_control_plane_protected();
     unsafe {
         use riscv::register::{
             mcounteren, mepc,
@@ -196,32 +292,298 @@ extern "C" fn main(_mhartid: usize) -> ! {
         sstatus::set_sum();
         mstatus::set_mpp(MPP::Supervisor);
         println!("[rustsbi] entering supervisor mode...");
-        mepc::write(s_mode_start as usize);
-        rustsbi::enter_privileged(mhartid::read(), dtb_pa)
+        hal::Uartlite::drain();
+        // hart 0 带着设备树进入内核，其它硬件线程按唤醒时确定的入口和 a1 进入
+        let (entry, arg) = match mhartid::read() {
+            0 => (BOOT_ENTRY.load(Ordering::Acquire), dtb_pa),
+            _ => hart::current().start_params(),
+        };
+        mepc::write(entry);
+        hart::enter_supervisor(mhartid::read(), arg)
     }
 }
 
-#[naked]
-#[link_section = ".text"] // must add link section for all naked functions
-unsafe extern "C" fn s_mode_start() -> ! {
-    asm!(
-        "
-.align 2
-1:  auipc ra, %pcrel_hi(1f)
-    ld ra, %pcrel_lo(1b)(ra)
-    jr ra
-.align  3
-1:  .dword 0x100200000
-    ",
-        options(noreturn)
-    )
+// 启动倒计时，期间按'x'通过串口下载内核，按其它键进入监控程序
+#[cfg(any(feature = "serial_boot", feature = "monitor"))]
+#[cfg_attr(not(feature = "monitor"), allow(unused_variables))]
+fn boot_menu(serial: &mut hal::Serial, dtb_pa: usize) {
+    use embedded_hal::serial::Read;
+    // 等待按键的秒数
+    const BOOT_DELAY: u64 = 3;
+
+    let clint = hal::clint();
+    #[cfg(feature = "serial_boot")]
+    println!("[rustsbi] Press 'x' within {}s to download kernel via XMODEM/YMODEM", BOOT_DELAY);
+    #[cfg(feature = "monitor")]
+    println!("[rustsbi] Press any key within {}s to enter monitor", BOOT_DELAY);
+    let deadline = clint.get_mtime() + BOOT_DELAY * TIMEBASE_FREQUENCY;
+    let key = loop {
+        if clint.get_mtime() >= deadline {
+            return;
+        }
+        if let Ok(key) = serial.read() {
+            break key;
+        }
+    };
+    match key {
+        #[cfg(feature = "serial_boot")]
+        b'x' | b'X' => serial_download(serial),
+        #[cfg(feature = "monitor")]
+        _ => {
+            if let Some(entry) = monitor::run(serial, dtb_pa) {
+                BOOT_ENTRY.store(entry, Ordering::Release);
+                println!("[rustsbi] Kernel entry: {:#x}", entry);
+            }
+        }
+        #[cfg(not(feature = "monitor"))]
+        _ => {}
+    }
 }
 
-fn init_pmp() {
+// 通过 XMODEM/YMODEM 从串口接收内核写到内核入口
+#[cfg(feature = "serial_boot")]
+pub fn serial_download<S>(serial: &mut S)
+where
+    S: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+{
+    // 内核最大长度
+    const PAYLOAD_MAX_SIZE: usize = 64 * 1024 * 1024;
+    // 传输失败后最多再试的次数
+    const MAX_ATTEMPTS: usize = 3;
+
+    let clint = hal::clint();
+    for attempt in 1..=MAX_ATTEMPTS {
+        println!("[rustsbi-xmodem] Waiting for sender, kernel will be written to {:#x}", KERNEL_ENTRY);
+        let buf = unsafe { core::slice::from_raw_parts_mut(KERNEL_ENTRY as *mut u8, PAYLOAD_MAX_SIZE) };
+        let mut receiver = xmodem::Receiver::new(serial, || clint.get_mtime(), TIMEBASE_FREQUENCY);
+        match receiver.receive(buf) {
+            Ok(received) => {
+                unsafe { asm!("fence.i") };
+                println!(
+                    "[rustsbi-xmodem] Received {} bytes via {}",
+                    received.len,
+                    if received.ymodem { "YMODEM" } else { "XMODEM" }
+                );
+                return;
+            }
+            Err(xmodem::Error::Timeout) => {
+                // 还没开始写入，继续用原来的内核启动
+                println!("[rustsbi-xmodem] No sender, booting existing kernel");
+                return;
+            }
+            // 传输失败时内核可能只写了一半，只能重传
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                println!("[rustsbi-xmodem] Transfer failed: {:?}, retrying", e)
+            }
+            Err(e) => println!("[rustsbi-xmodem] Transfer failed: {:?}", e),
+        }
+    }
+    // 不再重试，照常启动；内存中的内核可能已经被写坏
+    println!(
+        "[rustsbi-xmodem] Giving up after {} attempts, booting existing kernel, which may be incomplete",
+        MAX_ATTEMPTS
+    );
+}
+
+// entry.S 中为内嵌设备树保留的空间
+const EMBEDDED_DTB_SIZE: usize = 0x4000;
+
+// 按优先级从高到低：内嵌设备树只读，固件镜像和控制平面禁止访问，设备树中配置的额外区域，其余地址全部允许。
+// 固件区域没有加锁，加锁会让 M 态也受限制
+fn init_pmp(dtb_pa: usize) {
+    use pmp::{cfg, PmpManager, Region};
+    extern "C" {
+        static _stext: u8;
+        static _stack_start: u8;
+    }
+    let hartid = mhartid::read();
+    let info = pmp::probe();
+    // 没有 PMP 时 S 态本来就能访问全部地址，不能访问 PMP 寄存器
+    if info.entries == 0 {
+        println!("[rustsbi-pmp] hart {}: no PMP entries, firmware is not protected", hartid);
+        flush_translation();
+        return;
+    }
+    let stext = unsafe { &_stext } as *const _ as usize;
+    let stack_start = unsafe { &_stack_start } as *const _ as usize;
+    // 内核从 a1 得到的设备树在固件镜像里面，S 态需要能读；按粒度向外扩展
+    let granularity = info.granularity.max(4);
+    let dtb_start = dtb_pa & !(granularity - 1);
+    let dtb_end = (dtb_pa + EMBEDDED_DTB_SIZE + granularity - 1) & !(granularity - 1);
+    #[cfg(feature = "smepmp")]
+    if let Some(manager) = smepmp_layout(info, stext, dtb_end, stack_start, dtb_pa) {
+        pmp::smepmp::apply(&manager);
+        if hartid == 0 {
+            println!("[rustsbi-pmp] Smepmp enabled, mseccfg: {:#x}", pmp::smepmp::read());
+        }
+        flush_translation();
+        return;
+    }
+    let firmware = Region {
+        start: stext,
+        size: stack_start - stext,
+        perm: 0,
+    };
+    let dtb = Region {
+        start: dtb_start,
+        size: dtb_end - dtb_start,
+        perm: cfg::R,
+    };
+    let mut manager = PmpManager::new(info);
+    let essential = manager
+        .set_default(cfg::R | cfg::W | cfg::X)
+        .and_then(|_| manager.add(dtb))
+        .and_then(|_| manager.add(firmware));
+    if let Err(e) = essential {
+        // 放不下时至少让 S 态能访问全部地址，否则没有匹配的访问都会失败
+        println!(
+            "[rustsbi-pmp] hart {}: firmware is not protected with {} entries: {}",
+            hartid, info.entries, e
+        );
+        let mut manager = PmpManager::new(info);
+        let _ = manager.set_default(cfg::R | cfg::W | cfg::X);
+        manager.apply();
+        flush_translation();
+        return;
+    }
+    // 控制平面只由固件通过厂商扩展访问，S 态直接写寄存器会绕过参数检查
+    for region in control_plane_regions(0) {
+        if let Err(e) = manager.add(region) {
+            warn_region_skipped(region, e);
+        }
+    }
+    for region in unsafe { pmp::configured_regions(dtb_pa) } {
+        if let Err(e) = manager.add(region) {
+            warn_region_skipped(region, e);
+        }
+    }
+    manager.apply();
+    flush_translation();
+}
+
+// 已注册的控制平面寄存器所在的区域，权限为 `perm`
+fn control_plane_regions(perm: u8) -> impl Iterator<Item = pmp::Region> {
+    ecall::lrv::control_plane_regions()
+        .into_iter()
+        .map(move |(start, size)| pmp::Region { start, size, perm })
+}
+
+// 按 S 态的权限实际读一次控制平面的每个区域，确认 PMP 会让它产生访存异常
+fn check_control_plane_protected() {
+    if pmp::implemented() == 0 {
+        return;
+    }
+    for (start, size) in ecall::lrv::control_plane_regions() {
+        if !pmp::supervisor_load_faults(start) {
+            println!(
+                "[rustsbi-pmp] hart {}: control plane {:#x}..{:#x} is accessible from S-mode",
+                mhartid::read(),
+                start,
+                start.wrapping_add(size)
+            );
+        }
+    }
+}
+
+fn warn_region_skipped(region: pmp::Region, e: pmp::Error) {
+    println!(
+        "[rustsbi-pmp] hart {}: region {:#x}..{:#x} skipped: {}",
+        mhartid::read(),
+        region.start,
+        region.start.wrapping_add(region.size),
+        e
+    );
+}
+
+// Smepmp 下按优先级从高到低：
+// - 固件开头到内嵌设备树末尾：加锁的共享只读区域，M 和 S 态都只能读；
+// - 固件代码：M 态只能执行；
+// - 只读数据到栈顶：M 态读写；
+// - 为 DBCN 等访问 S 态缓冲区保留的窗口；
+// - 控制平面寄存器：M 态读写；
+// - 设备树中配置的额外区域，只对 S 态；
+// - 设备树中的内存：只有 S 态可以访问，M 态不能读写也不能执行；
+// - 其余地址（外设）：M 和 S 态共享读写。
+// 放不下或者设备树中没有内存节点时返回 None ，使用普通 PMP 的配置
+#[cfg(feature = "smepmp")]
+fn smepmp_layout(
+    info: pmp::PmpInfo,
+    stext: usize,
+    dtb_end: usize,
+    stack_start: usize,
+    dtb_pa: usize,
+) -> Option<pmp::PmpManager> {
+    use pmp::{cfg, PmpManager, Region};
+    extern "C" {
+        static _etext: u8;
+    }
+    let hartid = mhartid::read();
+    let etext = unsafe { &_etext } as *const _ as usize;
+    let memory = match unsafe { dtb::load(dtb_pa) }.and_then(|dt| dtb::memory(&dt)) {
+        Some((start, size)) => Region {
+            start,
+            size,
+            perm: cfg::R | cfg::W | cfg::X,
+        },
+        None => {
+            println!("[rustsbi-pmp] hart {}: no memory node in device tree, Smepmp disabled", hartid);
+            return None;
+        }
+    };
+    let mut manager = PmpManager::new(info);
+    let firmware = manager
+        .set_default(cfg::W | cfg::X)
+        .and_then(|_| {
+            manager.add(Region {
+                start: stext,
+                size: dtb_end - stext,
+                perm: cfg::L | cfg::R | cfg::W | cfg::X,
+            })
+        })
+        .and_then(|_| {
+            manager.add(Region {
+                start: dtb_end,
+                size: etext - dtb_end,
+                perm: cfg::L | cfg::X,
+            })
+        })
+        .and_then(|_| {
+            manager.add(Region {
+                start: etext,
+                size: stack_start - etext,
+                perm: cfg::L | cfg::R | cfg::W,
+            })
+        })
+        .and_then(|_| manager.reserve_window());
+    if let Err(e) = firmware {
+        println!("[rustsbi-pmp] hart {}: Smepmp layout does not fit: {}", hartid, e);
+        return None;
+    }
+    // 控制平面：只有 M 态可以读写
+    for region in control_plane_regions(cfg::L | cfg::R | cfg::W) {
+        if let Err(e) = manager.add(region) {
+            println!("[rustsbi-pmp] hart {}: Smepmp layout does not fit: {}", hartid, e);
+            return None;
+        }
+    }
+    for region in unsafe { pmp::configured_regions(dtb_pa) } {
+        // 额外区域不能挤掉后面的内存区域
+        let mut trial = manager.clone();
+        let fits = trial.add(region).and_then(|_| trial.add(memory));
+        if let Err(e) = fits.and_then(|_| manager.add(region)) {
+            warn_region_skipped(region, e);
+        }
+    }
+    if let Err(e) = manager.add(memory) {
+        println!("[rustsbi-pmp] hart {}: Smepmp layout does not fit: {}", hartid, e);
+        return None;
+    }
+    Some(manager)
+}
+
+// 由于该平台使用软启动和复位，需要关闭先前程序可能打开的分页
+fn flush_translation() {
     use riscv::asm;
-    use riscv::register::{pmpaddr0, pmpcfg0};
-    pmpcfg0::write(0x1f);
-    pmpaddr0::write(usize::MAX);
     unsafe {
         asm!("csrwi satp, 0x0");
         asm::sfence_vma_all();
@@ -230,8 +592,7 @@ fn init_pmp() {
 
 unsafe fn count_harts(dtb_pa: usize) -> usize {
     println!("[rustsbi-dtb] dtb_pa addr: {:#x}", dtb_pa);
-    use device_tree::{DeviceTree, Node};
-    const DEVICE_TREE_MAGIC: u32 = 0xD00DFEED;
+    use device_tree::Node;
     // 遍历“cpu_map”结构
     // 这个结构的子结构是“处理核簇”（cluster）
     // 每个“处理核簇”的子结构分别表示一个处理器核
@@ -247,38 +608,13 @@ unsafe fn count_harts(dtb_pa: usize) -> usize {
         }
         tot
     }
-    #[repr(C)]
-    struct DtbHeader {
-        magic: u32,
-        size: u32,
-    }
-    let header = &*(dtb_pa as *const DtbHeader);
-    // from_be 是大小端序的转换（from big endian）
-    let magic = u32::from_be(header.magic);
-    if magic == DEVICE_TREE_MAGIC {
-        let size = u32::from_be(header.size);
-        // 拷贝数据，加载并遍历
-        let data = core::slice::from_raw_parts(dtb_pa as *const u8, size as usize);
-        if let Ok(dt) = DeviceTree::load(data) {
-            if let Some(cpu_map) = dt.find("/cpus/cpu-map") {
-                return enumerate_cpu_map(cpu_map);
-            }
+    if let Some(dt) = dtb::load(dtb_pa) {
+        if let Some(cpu_map) = dt.find("/cpus/cpu-map") {
+            return enumerate_cpu_map(cpu_map);
         }
     }
     // 如果DTB的结构不对（读不到/cpus/cpu-map），返回默认的8个核
-    let ans = compiled_max_hartid();
+    let ans = hart::compiled_max_hart_id();
     println!("[rustsbi-dtb] Could not read '/cpus/cpu-map' from 'dtb_pa' device tree root; assuming {} cores", ans);
     ans
 }
-
-#[inline]
-fn compiled_max_hartid() -> usize {
-    let ans;
-    unsafe {
-        asm!("
-        lui     {ans}, %hi(_max_hart_id)
-        add     {ans}, {ans}, %lo(_max_hart_id)
-    ", ans = out(reg) ans)
-    };
-    ans
-}
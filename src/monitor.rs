@@ -0,0 +1,457 @@
+// M 态交互式监控程序，方便新比特流上板调试
+// 数字参数都按十六进制解析，可以带 0x 前缀
+
+use core::arch::asm;
+use embedded_hal::serial::{Read, Write};
+use rustsbi::{print, println};
+
+use crate::hal;
+
+const LINE_MAX: usize = 128;
+// md 一次最多显示的字数
+const MD_MAX_COUNT: usize = 1024;
+
+const HELP: &str = "\
+help                      show this message
+md <addr> [count]         display `count` 32-bit words from `addr`
+mw <addr> <value>         write a 32-bit word to `addr`
+csr <name|num> [value]    read or write a CSR
+harts                     show hart status
+dtb                       dump the device tree
+pmp                       dump PMP configuration
+stack                     show M-mode stack usage of each hart
+stats                     show trap and SBI call statistics of each hart
+uart                      show serial error counters
+boot [addr]               leave the monitor and boot the kernel (at `addr`)
+reset                     reset the system";
+
+/// 运行监控程序，直到输入 `boot`；返回 `boot` 指定的内核入口
+pub fn run<S: Read<u8> + Write<u8>>(serial: &mut S, dtb_pa: usize) -> Option<usize> {
+    println!("[rustsbi-monitor] Type 'help' for commands");
+    let mut line = [0u8; LINE_MAX];
+    loop {
+        print!("rustsbi> ");
+        let len = read_line(serial, &mut line);
+        let line = match core::str::from_utf8(&line[..len]) {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+        let mut args = line.split_whitespace();
+        let cmd = match args.next() {
+            Some(cmd) => cmd,
+            None => continue,
+        };
+        let result = match cmd {
+            "help" => {
+                println!("{}", HELP);
+                #[cfg(feature = "serial_boot")]
+                println!("load                      download the kernel via XMODEM/YMODEM");
+                Ok(())
+            }
+            "md" => memory_display(args),
+            "mw" => memory_write(args),
+            "csr" => csr(args),
+            "harts" => {
+                harts();
+                Ok(())
+            }
+            "dtb" => {
+                unsafe { dump_dtb(dtb_pa) };
+                Ok(())
+            }
+            "pmp" => {
+                pmp();
+                Ok(())
+            }
+            "stack" => {
+                stack();
+                Ok(())
+            }
+            "stats" => {
+                crate::stats::dump();
+                Ok(())
+            }
+            "uart" => {
+                let counts = hal::Uartlite::error_counts();
+                println!(
+                    "overrun {}, frame {}, parity {}, dropped {}",
+                    counts.overrun, counts.frame, counts.parity, counts.dropped
+                );
+                Ok(())
+            }
+            "boot" => match args.next().map(parse_number) {
+                Some(Some(addr)) => return Some(addr),
+                Some(None) => Err("invalid address"),
+                None => return None,
+            },
+            #[cfg(feature = "serial_boot")]
+            "load" => {
+                crate::serial_download(serial);
+                Ok(())
+            }
+            "reset" => {
+                use rustsbi::Reset;
+                hal::Reset.system_reset(
+                    rustsbi::reset::RESET_TYPE_COLD_REBOOT,
+                    rustsbi::reset::RESET_REASON_NO_REASON,
+                );
+                Ok(())
+            }
+            _ => Err("unknown command, type 'help' for commands"),
+        };
+        if let Err(e) = result {
+            println!("{}: {}", cmd, e);
+        }
+    }
+}
+
+// 读入一行并回显，支持退格
+fn read_line<S: Read<u8> + Write<u8>>(serial: &mut S, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = match nb::block!(serial.read()) {
+            Ok(byte) => byte,
+            Err(_) => continue,
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                let _ = nb::block!(serial.write(b'\n'));
+                return len;
+            }
+            0x08 | 0x7f => {
+                if len > 0 {
+                    len -= 1;
+                    for &b in b"\x08 \x08" {
+                        let _ = nb::block!(serial.write(b));
+                    }
+                }
+            }
+            0x20..=0x7e if len < buf.len() => {
+                buf[len] = byte;
+                len += 1;
+                let _ = nb::block!(serial.write(byte));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    let s = s.trim_start_matches("0x");
+    usize::from_str_radix(s, 16).ok()
+}
+
+fn next_number<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<usize, &'static str> {
+    let arg = args.next().ok_or("missing argument")?;
+    parse_number(arg).ok_or("invalid number")
+}
+
+fn memory_display<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<(), &'static str> {
+    let addr = next_number(&mut args)? & !0b11;
+    let count = match args.next() {
+        Some(arg) => parse_number(arg).ok_or("invalid number")?,
+        None => 16,
+    };
+    if count > MD_MAX_COUNT {
+        return Err("count too large");
+    }
+    // 最后一个字也不能越过地址空间的末尾
+    if count != 0 && addr.checked_add((count - 1) * 4 + 3).is_none() {
+        return Err("address out of range");
+    }
+    for i in 0..count {
+        let word_addr = addr + i * 4;
+        if i % 4 == 0 {
+            print!("{:016x}:", word_addr);
+        }
+        match read_word(word_addr) {
+            Some(word) => print!(" {:08x}", word),
+            None => {
+                println!("");
+                println!("access fault at {:#x}", word_addr);
+                return Ok(());
+            }
+        }
+        if i % 4 == 3 || i + 1 == count {
+            println!("");
+        }
+    }
+    Ok(())
+}
+
+fn memory_write<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<(), &'static str> {
+    let addr = next_number(&mut args)?;
+    let value = next_number(&mut args)?;
+    if addr & 0b11 != 0 {
+        return Err("address must be 4-byte aligned");
+    }
+    if !write_word(addr, value as u32) {
+        return Err("access fault");
+    }
+    Ok(())
+}
+
+// 临时把 mtvec 指向标号 1 执行 `$insn` ，产生异常时跳过去，fault 保持为 1 ；做法和 pmp::probe_addr 相同。
+// 监控程序运行时 M 态中断是关闭的，这期间只会有 `$insn` 自己产生的异常；返回是否没有产生异常
+macro_rules! guarded_asm {
+    ($insn:expr, $($operands:tt)*) => {{
+        let fault: usize;
+        asm!(
+            "csrr {mstatus}, mstatus",
+            "la {mtvec}, 1f",
+            "csrrw {mtvec}, mtvec, {mtvec}",
+            "li {fault}, 1",
+            $insn,
+            "li {fault}, 0",
+            ".align 2",
+            "1:",
+            "csrw mtvec, {mtvec}",
+            "csrw mstatus, {mstatus}",
+            $($operands)*
+            mstatus = out(reg) _,
+            mtvec = out(reg) _,
+            fault = out(reg) fault,
+        );
+        fault == 0
+    }};
+}
+
+// 读一个字，地址不可访问时返回 None
+fn read_word(addr: usize) -> Option<u32> {
+    let value: usize;
+    let ok = unsafe {
+        guarded_asm!("lwu {value}, 0({addr})", addr = in(reg) addr, value = out(reg) value,)
+    };
+    if ok {
+        Some(value as u32)
+    } else {
+        None
+    }
+}
+
+// 写一个字，地址不可访问时返回 false
+fn write_word(addr: usize, value: u32) -> bool {
+    unsafe {
+        guarded_asm!("sw {value}, 0({addr})", addr = in(reg) addr, value = in(reg) value as usize,)
+    }
+}
+
+// CSR 编号必须写在指令里，只能对已知的 CSR 逐个生成访问代码；
+// 核上没有实现的 CSR 访问时产生非法指令异常，报告为没有实现
+macro_rules! csr_table {
+    ($($name:ident = $num:literal,)*) => {
+        const CSR_NAMES: &[(&str, usize)] = &[$((stringify!($name), $num),)*];
+
+        fn csr_read(csr: usize) -> Result<usize, &'static str> {
+            let value: usize;
+            let ok = match csr {
+                $($num => unsafe {
+                    guarded_asm!(concat!("csrr {value}, ", stringify!($num)), value = out(reg) value,)
+                },)*
+                _ => return Err("unsupported CSR"),
+            };
+            if ok {
+                Ok(value)
+            } else {
+                Err("CSR not implemented")
+            }
+        }
+
+        fn csr_write(csr: usize, value: usize) -> Result<(), &'static str> {
+            let ok = match csr {
+                $($num => unsafe {
+                    guarded_asm!(concat!("csrw ", stringify!($num), ", {value}"), value = in(reg) value,)
+                },)*
+                _ => return Err("unsupported CSR"),
+            };
+            if ok {
+                Ok(())
+            } else {
+                Err("CSR not implemented")
+            }
+        }
+    };
+}
+
+csr_table! {
+    sstatus = 0x100,
+    sie = 0x104,
+    stvec = 0x105,
+    scounteren = 0x106,
+    sscratch = 0x140,
+    sepc = 0x141,
+    scause = 0x142,
+    stval = 0x143,
+    sip = 0x144,
+    satp = 0x180,
+    mstatus = 0x300,
+    misa = 0x301,
+    medeleg = 0x302,
+    mideleg = 0x303,
+    mie = 0x304,
+    mtvec = 0x305,
+    mcounteren = 0x306,
+    mscratch = 0x340,
+    mepc = 0x341,
+    mcause = 0x342,
+    mtval = 0x343,
+    mip = 0x344,
+    pmpcfg0 = 0x3a0,
+    pmpcfg2 = 0x3a2,
+    pmpaddr0 = 0x3b0,
+    pmpaddr1 = 0x3b1,
+    pmpaddr2 = 0x3b2,
+    pmpaddr3 = 0x3b3,
+    pmpaddr4 = 0x3b4,
+    pmpaddr5 = 0x3b5,
+    pmpaddr6 = 0x3b6,
+    pmpaddr7 = 0x3b7,
+    pmpaddr8 = 0x3b8,
+    pmpaddr9 = 0x3b9,
+    pmpaddr10 = 0x3ba,
+    pmpaddr11 = 0x3bb,
+    pmpaddr12 = 0x3bc,
+    pmpaddr13 = 0x3bd,
+    pmpaddr14 = 0x3be,
+    pmpaddr15 = 0x3bf,
+    mcycle = 0xb00,
+    minstret = 0xb02,
+    mvendorid = 0xf11,
+    marchid = 0xf12,
+    mimpid = 0xf13,
+    mhartid = 0xf14,
+}
+
+fn csr<'a>(mut args: impl Iterator<Item = &'a str>) -> Result<(), &'static str> {
+    let name = args.next().ok_or("missing argument")?;
+    let csr = CSR_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, num)| *num)
+        .or_else(|| parse_number(name))
+        .ok_or("unknown CSR")?;
+    match args.next() {
+        None => {
+            let value = csr_read(csr)?;
+            println!("{} = {:#x}", name, value);
+        }
+        Some(value) => {
+            let value = parse_number(value).ok_or("invalid number")?;
+            // 编号最高两位为 11 的 CSR 是只读的
+            if csr >> 10 == 0b11 {
+                return Err("read-only CSR");
+            }
+            csr_write(csr, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn harts() {
+    let clint = hal::clint();
+    let max_hart_id = crate::hart::max_hart_id();
+    println!("current hart: {}", riscv::register::mhartid::read());
+    println!("mtime: {:#x}", clint.get_mtime());
+    for hart_id in 0..=max_hart_id {
+        // 没有启动过的硬件线程栈顶为 0
+        let stack_top = crate::hart::context(hart_id).map(|context| context.stack_top()).unwrap_or(0);
+        println!(
+            "hart {}: msip {}, mtimecmp {:#x}, stack top {:#x}",
+            hart_id,
+            clint.get_soft(hart_id) as u8,
+            clint.get_mtimecmp(hart_id),
+            stack_top
+        );
+    }
+}
+
+fn stack() {
+    use crate::hart;
+    for hart_id in 0..=hart::max_hart_id() {
+        match hart::context(hart_id).and_then(|context| context.stack_usage()) {
+            Some(usage) => println!(
+                "hart {}: {} / {} bytes{}",
+                hart_id,
+                usage.used,
+                usage.size,
+                if usage.overflowed { ", overflowed" } else { "" }
+            ),
+            None => println!("hart {}: not started", hart_id),
+        }
+    }
+}
+
+fn pmp() {
+    use crate::pmp::{self, cfg};
+    if pmp::implemented() == 0 {
+        println!("PMP not implemented");
+    }
+    for i in 0..pmp::implemented() {
+        let config = pmp::read_cfg(i);
+        let mode = match config & cfg::A_MASK {
+            cfg::A_OFF => "OFF",
+            cfg::A_TOR => "TOR",
+            cfg::A_NA4 => "NA4",
+            _ => "NAPOT",
+        };
+        println!(
+            "pmp{:<2} {}{}{}{} {:<5} pmpaddr {:#x}",
+            i,
+            if config & cfg::L != 0 { 'L' } else { '-' },
+            if config & cfg::R != 0 { 'R' } else { '-' },
+            if config & cfg::W != 0 { 'W' } else { '-' },
+            if config & cfg::X != 0 { 'X' } else { '-' },
+            mode,
+            pmp::read_addr(i)
+        );
+    }
+}
+
+unsafe fn dump_dtb(dtb_pa: usize) {
+    use device_tree::Node;
+    fn dump_node(node: &Node, depth: usize) {
+        let indent = depth * 4;
+        println!("{:indent$}{} {{", "", if node.name.is_empty() { "/" } else { &node.name }, indent = indent);
+        for (name, value) in node.props.iter() {
+            print!("{:indent$}{}", "", name, indent = indent + 4);
+            dump_prop(value);
+        }
+        for child in node.children.iter() {
+            dump_node(child, depth + 1);
+        }
+        println!("{:indent$}}};", "", indent = indent);
+    }
+    // 可打印的按字符串列表输出，否则按 32 位单元输出
+    fn dump_prop(value: &[u8]) {
+        if value.is_empty() {
+            println!(";");
+        } else if value.last() == Some(&0)
+            && value[0] != 0
+            && value.iter().all(|&b| b == 0 || (0x20..0x7f).contains(&b))
+        {
+            print!(" =");
+            for (i, s) in value[..value.len() - 1].split(|&b| b == 0).enumerate() {
+                let s = core::str::from_utf8(s).unwrap_or("");
+                print!("{} \"{}\"", if i == 0 { "" } else { "," }, s);
+            }
+            println!(";");
+        } else if value.len() % 4 == 0 {
+            print!(" = <");
+            for (i, cell) in value.chunks(4).enumerate() {
+                let cell = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+                print!("{}{:#x}", if i == 0 { "" } else { " " }, cell);
+            }
+            println!(">;");
+        } else {
+            print!(" = [");
+            for (i, b) in value.iter().enumerate() {
+                print!("{}{:02x}", if i == 0 { "" } else { " " }, b);
+            }
+            println!("];");
+        }
+    }
+    match crate::dtb::load(dtb_pa) {
+        Some(dt) => dump_node(&dt.root, 0),
+        None => println!("no valid device tree at {:#x}", dtb_pa),
+    }
+}
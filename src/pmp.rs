@@ -0,0 +1,506 @@
+// PMP 配置的读取、检查和设置
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::dtb;
+
+/// RV64 最多 16 项 PMP
+pub const PMP_COUNT: usize = 16;
+
+#[allow(dead_code)]
+pub mod cfg {
+    pub const R: u8 = 1 << 0;
+    pub const W: u8 = 1 << 1;
+    pub const X: u8 = 1 << 2;
+    pub const A_MASK: u8 = 0b11 << 3;
+    pub const A_OFF: u8 = 0 << 3;
+    pub const A_TOR: u8 = 1 << 3;
+    pub const A_NA4: u8 = 2 << 3;
+    pub const A_NAPOT: u8 = 3 << 3;
+    pub const L: u8 = 1 << 7;
+}
+
+/// 第 `index` 项的配置字节
+pub fn read_cfg(index: usize) -> u8 {
+    let cfg: usize;
+    // RV64 只有偶数编号的 pmpcfg
+    unsafe {
+        if index < 8 {
+            asm!("csrr {0}, pmpcfg0", out(reg) cfg);
+        } else {
+            asm!("csrr {0}, pmpcfg2", out(reg) cfg);
+        }
+    }
+    (cfg >> ((index % 8) * 8)) as u8
+}
+
+macro_rules! pmpaddr_csr {
+    ($($i:literal)*) => {
+        /// 第 `index` 项的地址寄存器
+        pub fn read_addr(index: usize) -> usize {
+            let addr: usize;
+            match index {
+                $($i => unsafe { asm!(concat!("csrr {0}, pmpaddr", $i), out(reg) addr) },)*
+                _ => panic!("invalid pmpaddr index {}", index),
+            }
+            addr
+        }
+
+        fn write_addr(index: usize, addr: usize) {
+            match index {
+                $($i => unsafe { asm!(concat!("csrw pmpaddr", $i, ", {0}"), in(reg) addr) },)*
+                _ => panic!("invalid pmpaddr index {}", index),
+            }
+        }
+
+        // 写入全 1 后读回，再恢复原值；访问这个 CSR 产生异常时返回 None
+        fn probe_addr(index: usize) -> Option<usize> {
+            let value: usize;
+            let fault: usize;
+            match index {
+                // 临时把 mtvec 指向标号 1 ，异常时跳过去，fault 保持为 1
+                $($i => unsafe {
+                    asm!(
+                        "csrr {mstatus}, mstatus",
+                        "la {mtvec}, 1f",
+                        "csrrw {mtvec}, mtvec, {mtvec}",
+                        "li {fault}, 1",
+                        concat!("csrrw {old}, pmpaddr", $i, ", {ones}"),
+                        concat!("csrrw {value}, pmpaddr", $i, ", {old}"),
+                        "li {fault}, 0",
+                        ".align 2",
+                        "1:",
+                        "csrw mtvec, {mtvec}",
+                        "csrw mstatus, {mstatus}",
+                        mstatus = out(reg) _,
+                        mtvec = out(reg) _,
+                        old = out(reg) _,
+                        ones = in(reg) usize::MAX,
+                        value = out(reg) value,
+                        fault = out(reg) fault,
+                    )
+                },)*
+                _ => panic!("invalid pmpaddr index {}", index),
+            }
+            if fault == 0 {
+                Some(value)
+            } else {
+                None
+            }
+        }
+    };
+}
+
+pmpaddr_csr!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
+fn write_cfg(index: usize, config: u8) {
+    let shift = (index % 8) * 8;
+    unsafe {
+        if index < 8 {
+            let old: usize;
+            asm!("csrr {0}, pmpcfg0", out(reg) old);
+            asm!("csrw pmpcfg0, {0}", in(reg) old & !(0xff << shift) | (config as usize) << shift);
+        } else {
+            let old: usize;
+            asm!("csrr {0}, pmpcfg2", out(reg) old);
+            asm!("csrw pmpcfg2, {0}", in(reg) old & !(0xff << shift) | (config as usize) << shift);
+        }
+    }
+}
+
+// 探测到的 PMP 项数，读取配置时不访问没有实现的 CSR
+static IMPLEMENTED: AtomicUsize = AtomicUsize::new(0);
+static GRANULARITY: AtomicUsize = AtomicUsize::new(4);
+// 为固件访问 S 态缓冲区保留的项，没有时为 usize::MAX
+static WINDOW: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// 硬件实现的 PMP 项数和粒度
+#[derive(Debug, Clone, Copy)]
+pub struct PmpInfo {
+    pub entries: usize,
+    /// 最小的保护粒度，字节
+    pub granularity: usize,
+}
+
+/// 探测当前硬件线程实现的 PMP 项数和粒度，不改变现有配置
+///
+/// 没有实现的地址寄存器可能恒为零，也可能访问时产生非法指令异常（如只有 8 项的 Rocket 访问 pmpaddr8），
+/// 两种情况都算作没有实现；项数为 0 时粒度也为 0
+pub fn probe() -> PmpInfo {
+    let entries = (0..PMP_COUNT)
+        .take_while(|&index| !matches!(probe_addr(index), None | Some(0)))
+        .count();
+    IMPLEMENTED.store(entries, Ordering::Relaxed);
+    if entries == 0 {
+        return PmpInfo {
+            entries: 0,
+            granularity: 0,
+        };
+    }
+    // 粒度为 2^(G+2) 字节，第 0 项为 OFF 时地址寄存器的低 G 位读出为零
+    let cfg0: usize;
+    unsafe { asm!("csrr {0}, pmpcfg0", out(reg) cfg0) };
+    unsafe { asm!("csrw pmpcfg0, {0}", in(reg) cfg0 & !0xff) };
+    let ones = probe_addr(0).unwrap_or(usize::MAX);
+    unsafe { asm!("csrw pmpcfg0, {0}", in(reg) cfg0) };
+    let granularity = 1 << (ones.trailing_zeros().min(usize::BITS - 3) + 2);
+    GRANULARITY.store(granularity, Ordering::Relaxed);
+    PmpInfo {
+        entries,
+        granularity,
+    }
+}
+
+/// 上次探测到的 PMP 项数
+pub fn implemented() -> usize {
+    IMPLEMENTED.load(Ordering::Relaxed)
+}
+
+/// 一段物理地址区域，`perm` 为 pmpcfg 的 R/W/X 位；只有 Smepmp 的 M 态规则带 L 位
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: usize,
+    pub size: usize,
+    pub perm: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// 需要的项数超过了实现的项数
+    TooManyEntries,
+    /// 区域的起始地址或大小没有按 PMP 粒度对齐，或者大小为零
+    Misaligned,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::TooManyEntries => write!(f, "not enough PMP entries"),
+            Error::Misaligned => write!(f, "region is not aligned to PMP granularity"),
+        }
+    }
+}
+
+/// 按优先级从高到低依次加入区域，最后一次写进 PMP 寄存器
+///
+/// 大小为 2 的幂且按大小对齐的区域用一项 NAPOT，其它区域用 TOR，
+/// 起始地址正好是上一项的结束地址时 TOR 只需要一项
+#[derive(Clone)]
+pub struct PmpManager {
+    // 每项的配置字节和地址寄存器的值
+    entries: [(u8, usize); PMP_COUNT],
+    used: usize,
+    limit: usize,
+    granularity: usize,
+    // 其它区域都没有匹配时的权限，占用最后一项
+    default_perm: Option<u8>,
+    window: Option<usize>,
+}
+
+impl PmpManager {
+    pub fn new(info: PmpInfo) -> Self {
+        Self {
+            entries: [(cfg::A_OFF, 0); PMP_COUNT],
+            used: 0,
+            limit: info.entries.min(PMP_COUNT),
+            granularity: info.granularity.max(4),
+            default_perm: None,
+            window: None,
+        }
+    }
+
+    /// 其它区域都没有匹配时，S 和 U 态对全部地址空间有 `perm` 权限；
+    /// 这一项的优先级总是最低，先设置可以保证后面加入的区域不会把它挤掉
+    pub fn set_default(&mut self, perm: u8) -> Result<(), Error> {
+        if self.default_perm.is_none() && self.used + 1 > self.limit {
+            return Err(Error::TooManyEntries);
+        }
+        self.default_perm = Some(perm & (cfg::R | cfg::W | cfg::X));
+        Ok(())
+    }
+
+    pub fn add(&mut self, region: Region) -> Result<(), Error> {
+        let Region { start, size, perm } = region;
+        if size == 0 || start % self.granularity != 0 || size % self.granularity != 0 {
+            return Err(Error::Misaligned);
+        }
+        let perm = perm & (cfg::L | cfg::R | cfg::W | cfg::X);
+        if size.is_power_of_two() && size >= 8 && start % size == 0 {
+            // 低位连续 t 个 1 表示大小为 2^(t+3) 字节
+            let addr = (start >> 2) | ((size >> 3) - 1);
+            return self.push(&[(cfg::A_NAPOT | perm, addr)]);
+        }
+        let end = start.checked_add(size).ok_or(Error::Misaligned)?;
+        // TOR 的下界是上一项的地址寄存器，第 0 项的下界是 0
+        let lower_bound = match self.used {
+            0 => Some(0),
+            used => match self.entries[used - 1] {
+                (config, addr) if config & cfg::A_MASK == cfg::A_TOR => Some(addr << 2),
+                _ => None,
+            },
+        };
+        if lower_bound == Some(start) {
+            self.push(&[(cfg::A_TOR | perm, end >> 2)])
+        } else {
+            self.push(&[(cfg::A_OFF, start >> 2), (cfg::A_TOR | perm, end >> 2)])
+        }
+    }
+
+    /// 在当前位置保留一项，供 `open_window` 临时允许 M 态访问 S 态缓冲区
+    #[cfg(feature = "smepmp")]
+    pub fn reserve_window(&mut self) -> Result<(), Error> {
+        self.push(&[(cfg::A_OFF, 0)])?;
+        self.window = Some(self.used - 1);
+        Ok(())
+    }
+
+    fn push(&mut self, entries: &[(u8, usize)]) -> Result<(), Error> {
+        let reserved = self.default_perm.is_some() as usize;
+        if self.used + entries.len() + reserved > self.limit {
+            return Err(Error::TooManyEntries);
+        }
+        self.entries[self.used..self.used + entries.len()].copy_from_slice(entries);
+        self.used += entries.len();
+        Ok(())
+    }
+
+    /// 写进当前硬件线程的 PMP 寄存器，没有使用的项关闭
+    pub fn apply(&self) {
+        let mut entries = self.entries;
+        if let Some(perm) = self.default_perm {
+            // 低位全 1 的 NAPOT 覆盖整个地址空间
+            entries[self.used] = (cfg::A_NAPOT | perm, usize::MAX);
+        }
+        let mut cfg0 = 0usize;
+        let mut cfg2 = 0usize;
+        for (index, &(config, addr)) in entries.iter().enumerate().take(self.limit) {
+            write_addr(index, addr);
+            if index < 8 {
+                cfg0 |= (config as usize) << (index * 8);
+            } else {
+                cfg2 |= (config as usize) << ((index - 8) * 8);
+            }
+        }
+        unsafe {
+            asm!("csrw pmpcfg0, {0}", in(reg) cfg0);
+            if self.limit > 8 {
+                asm!("csrw pmpcfg2, {0}", in(reg) cfg2);
+            }
+        }
+        WINDOW.store(self.window.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+}
+
+/// 固件访问 S 态缓冲区期间打开的窗口，离开作用域时关闭
+pub struct SupervisorWindow {
+    index: Option<usize>,
+}
+
+/// Smepmp 下 M 态不能访问 S 态的内存，用保留的一项把 [addr, addr + len) 临时设为 M 和 S 态共享读写；
+/// 没有保留窗口时什么也不做。调用前应当已经检查过 S 态对这段内存的权限
+pub fn open_window(addr: usize, len: usize) -> SupervisorWindow {
+    let index = WINDOW.load(Ordering::Relaxed);
+    if index == usize::MAX || len == 0 {
+        return SupervisorWindow { index: None };
+    }
+    // 能覆盖整个缓冲区的最小 NAPOT 区域，比缓冲区大的部分只影响固件自己
+    let end = addr.saturating_add(len);
+    let mut size = len.next_power_of_two().max(GRANULARITY.load(Ordering::Relaxed)).max(8);
+    let mut start = addr & !(size - 1);
+    while start.saturating_add(size) < end && size < 1 << (usize::BITS - 1) {
+        size <<= 1;
+        start = addr & !(size - 1);
+    }
+    write_addr(index, (start >> 2) | ((size >> 3) - 1));
+    write_cfg(index, cfg::A_NAPOT | cfg::W | cfg::X);
+    SupervisorWindow { index: Some(index) }
+}
+
+impl Drop for SupervisorWindow {
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            write_cfg(index, cfg::A_OFF);
+            // 窗口期间 S 态在这段内存上没有执行权限，不能让 TLB 留着这个结果
+            unsafe { asm!("sfence.vma") };
+        }
+    }
+}
+
+/// Smepmp 的 M 态安全配置寄存器 mseccfg（0x747）
+#[cfg(feature = "smepmp")]
+pub mod smepmp {
+    use core::arch::asm;
+
+    use super::PmpManager;
+
+    /// 规则加锁后对 M 态生效，M 态只能从带 L 位的规则中取指
+    pub const MML: usize = 1 << 0;
+    /// M 态访问没有匹配任何规则的地址时失败
+    pub const MMWP: usize = 1 << 1;
+    /// 允许修改和删除加锁的规则
+    pub const RLB: usize = 1 << 2;
+
+    pub fn read() -> usize {
+        let value: usize;
+        unsafe { asm!("csrr {0}, 0x747", out(reg) value) };
+        value
+    }
+
+    pub fn enabled() -> bool {
+        read() & MML != 0
+    }
+
+    /// 按规范建议的启动顺序写入规则：先设置 RLB ，使软启动时之前加锁的规则也能改写，
+    /// 写完全部规则后设置 MMWP 和 MML ，最后清除 RLB ，之后加锁的规则直到复位都不能再修改
+    pub fn apply(manager: &PmpManager) {
+        unsafe { asm!("csrs 0x747, {0}", in(reg) RLB) };
+        manager.apply();
+        unsafe {
+            asm!("csrs 0x747, {0}", in(reg) MMWP | MML);
+            asm!("csrc 0x747, {0}", in(reg) RLB);
+        }
+    }
+}
+
+/// 设备树 `/chosen` 中 `lrv,pmp-regions` 给出的额外区域，
+/// 每个区域为 `<起始地址(2) 大小(2) 权限(1)>`，权限按 pmpcfg 的 R/W/X 位
+pub unsafe fn configured_regions(dtb_pa: usize) -> Vec<Region> {
+    let dt = match dtb::load(dtb_pa) {
+        Some(dt) => dt,
+        None => return Vec::new(),
+    };
+    let chosen = match dtb::find_path(&dt, "/chosen") {
+        Some(chosen) => chosen,
+        None => return Vec::new(),
+    };
+    let cells: Vec<u32> = chosen.prop_u32s("lrv,pmp-regions").collect();
+    cells
+        .chunks_exact(5)
+        .map(|cell| Region {
+            start: (cell[0] as usize) << 32 | cell[1] as usize,
+            size: (cell[2] as usize) << 32 | cell[3] as usize,
+            perm: cell[4] as u8 & (cfg::R | cfg::W | cfg::X),
+        })
+        .collect()
+}
+
+/// 第 `index` 项覆盖的物理地址范围 [start, end)；关闭或者没有实现的项返回 None
+pub fn region(index: usize) -> Option<(usize, usize)> {
+    if index >= implemented() {
+        return None;
+    }
+    let cfg = read_cfg(index);
+    let addr = read_addr(index);
+    match cfg & cfg::A_MASK {
+        cfg::A_TOR => {
+            let start = if index == 0 { 0 } else { read_addr(index - 1) << 2 };
+            Some((start, addr << 2))
+        }
+        cfg::A_NA4 => Some((addr << 2, (addr << 2) + 4)),
+        cfg::A_NAPOT => {
+            // 低位连续 t 个 1 表示大小为 2^(t+3) 字节
+            let t = addr.trailing_ones() as usize;
+            if t >= 62 {
+                // 全部地址空间
+                Some((0, usize::MAX))
+            } else {
+                let size = 1usize << (t + 3);
+                let start = (addr & !((1 << t) - 1)) << 2;
+                Some((start, start.saturating_add(size)))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// S 态能否读（或写）[addr, addr + len) 中的每个字节
+pub fn supervisor_can_access(addr: usize, len: usize, write: bool) -> bool {
+    supervisor_permits(addr, len, if write { cfg::W } else { cfg::R })
+}
+
+/// S 态能否从 `addr` 处取指
+pub fn supervisor_can_execute(addr: usize) -> bool {
+    supervisor_permits(addr, 2, cfg::X)
+}
+
+/// 按 S 态的权限（ `mstatus.MPRV` ）从 `addr` 实际读一个 64 位字，产生访存异常时返回 true 。
+/// 临时把 mtvec 指向读之后的标号，会改写 mepc 、mcause 和 mtval ，只在进入内核之前使用；
+/// 调用时 satp 必须为 0 ，否则按 S 态的页表翻译
+pub fn supervisor_load_faults(addr: usize) -> bool {
+    let fault: usize;
+    unsafe {
+        asm!(
+            "csrr {mstatus}, mstatus",
+            "csrc mstatus, {mpp}",
+            "csrs mstatus, {mprv_s}",
+            "la {mtvec}, 1f",
+            "csrrw {mtvec}, mtvec, {mtvec}",
+            "li {fault}, 1",
+            "ld {value}, 0({addr})",
+            "li {fault}, 0",
+            ".align 2",
+            "1:",
+            "csrw mtvec, {mtvec}",
+            "csrw mstatus, {mstatus}",
+            mstatus = out(reg) _,
+            mtvec = out(reg) _,
+            value = out(reg) _,
+            mpp = in(reg) 0b11usize << 11,
+            mprv_s = in(reg) 1usize << 17 | 0b01 << 11,
+            addr = in(reg) addr,
+            fault = out(reg) fault,
+        );
+    }
+    fault != 0
+}
+
+// 一项规则给 S 态的 R/W/X 权限
+fn supervisor_perm(config: u8) -> u8 {
+    let rwx = config & (cfg::R | cfg::W | cfg::X);
+    #[cfg(feature = "smepmp")]
+    if smepmp::enabled() {
+        // MML 下 R=0 W=1 的组合表示共享区域，带 L 位的其它规则只对 M 态
+        return match (config & cfg::L != 0, rwx) {
+            (false, r) if r == cfg::W => cfg::R,
+            (false, r) if r == cfg::W | cfg::X => cfg::R | cfg::W,
+            (false, r) => r,
+            (true, r) if r == cfg::W || r == cfg::W | cfg::X => cfg::X,
+            (true, r) if r == cfg::R | cfg::W | cfg::X => cfg::R,
+            (true, _) => 0,
+        };
+    }
+    rwx
+}
+
+// 按 PMP 的优先级规则逐段检查：编号最小的匹配项决定权限；有启用的项但没有项匹配时不允许访问
+fn supervisor_permits(addr: usize, len: usize, needed: u8) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let regions = || (0..implemented()).filter_map(|i| region(i).map(|r| (i, r)));
+    // 没有启用任何 PMP 项时，S 态可以访问所有地址
+    if regions().next().is_none() {
+        return true;
+    }
+    let mut pos = addr;
+    while pos < end {
+        let (index, (_, region_end)) = match regions().find(|&(_, (s, e))| s <= pos && pos < e) {
+            Some(found) => found,
+            None => return false,
+        };
+        if supervisor_perm(read_cfg(index)) & needed == 0 {
+            return false;
+        }
+        // 到这一项的末尾，或者到更高优先级的项开始的地方为止
+        let next = regions()
+            .take_while(|&(i, _)| i < index)
+            .filter(|&(_, (s, _))| s > pos)
+            .map(|(_, (s, _))| s)
+            .fold(region_end, usize::min);
+        pos = next;
+    }
+    true
+}
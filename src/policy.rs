@@ -0,0 +1,87 @@
+// 启动时的 LLC 和访存带宽划分策略
+//
+// 按以下顺序选择：
+// 1. 设备树 `/chosen/lrv,policy` 节点，`lrv,name` 为名字，`lrv,way-shares` 为每个标签占 LLC 路数的百分比，
+//    `lrv,buckets` 为每个标签的 `<size freq inc>`；
+// 2. 设备树 `/chosen` 中的 `lrv,policy` 字符串属性，选择下面的内置策略；
+// 3. 编译时环境变量 `LRV_POLICY` 选择的内置策略。
+use alloc::string::String;
+use alloc::vec::Vec;
+use control_plane::{Policy, TokenBucket};
+
+use crate::dtb;
+
+struct BuiltinPolicy {
+    name: &'static str,
+    way_shares: &'static [u32],
+    buckets: &'static [TokenBucket],
+}
+
+const NO_LIMIT: TokenBucket = TokenBucket {
+    size: 0,
+    freq: 0,
+    inc: 0,
+};
+
+const BUILTIN_POLICIES: &[BuiltinPolicy] = &[
+    BuiltinPolicy {
+        name: "llc-50-50",
+        way_shares: &[50, 50],
+        buckets: &[],
+    },
+    BuiltinPolicy {
+        name: "llc-75-25",
+        way_shares: &[75, 25],
+        buckets: &[],
+    },
+    // 标签 1 每 100 个周期最多 8 次访存
+    BuiltinPolicy {
+        name: "llc-75-25-bw",
+        way_shares: &[75, 25],
+        buckets: &[
+            NO_LIMIT,
+            TokenBucket {
+                size: 8,
+                freq: 100,
+                inc: 8,
+            },
+        ],
+    },
+];
+
+/// 按设备树和编译时配置选择策略，都没有时返回 None
+pub unsafe fn load(dtb_pa: usize) -> Option<Policy> {
+    let dt = dtb::load(dtb_pa);
+    if let Some(node) = dt.as_ref().and_then(|dt| dtb::find_path(dt, "/chosen/lrv,policy")) {
+        let name = node.node.prop_str("lrv,name").unwrap_or("chosen");
+        let cells: Vec<u32> = node.prop_u32s("lrv,buckets").collect();
+        return Some(Policy {
+            name: String::from(name),
+            way_shares: node.prop_u32s("lrv,way-shares").collect(),
+            buckets: cells
+                .chunks_exact(3)
+                .map(|cell| TokenBucket {
+                    size: cell[0] as usize,
+                    freq: cell[1] as usize,
+                    inc: cell[2] as usize,
+                })
+                .collect(),
+        });
+    }
+    let chosen = dt
+        .as_ref()
+        .and_then(|dt| dt.find("/chosen"))
+        .and_then(|chosen| chosen.prop_str("lrv,policy").ok());
+    let name = chosen.or(option_env!("LRV_POLICY"))?;
+    match BUILTIN_POLICIES.iter().find(|policy| policy.name == name) {
+        Some(policy) => Some(Policy {
+            name: String::from(policy.name),
+            way_shares: policy.way_shares.to_vec(),
+            buckets: policy.buckets.to_vec(),
+        }),
+        None => {
+            rustsbi::println!("[rustsbi-policy] Unknown partition policy '{}', ignored", name);
+            None
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// 陷入和 SBI 调用的统计
+//
+// 每个硬件线程按 mcause 、SBI 扩展和函数、模拟的指令类型统计次数，并累计在 M 态处理陷入用的周期数。
+// 计数器放在硬件线程的上下文中，只由所属的硬件线程增加，trap.S 的快速路径按编号直接访问；
+// S 态通过厂商扩展读取，也可以打印到控制台
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::register::mcycle;
+use rustsbi::println;
+
+use crate::hart;
+use crate::misaligned::MemoryUnit;
+
+// 按 mcause 统计：0 到 15 是异常，16 到 31 是中断
+const CAUSES: usize = 32;
+const INTERRUPT_BASE: usize = 16;
+const INTERRUPT_BIT: usize = 1 << (usize::BITS - 1);
+
+// 每个扩展统计的函数个数，编号更大的函数都计入最后一个
+const SBI_FUNCTIONS: usize = 16;
+
+// legacy 扩展的 EID 为 0 到 8 ，按 EID 当作函数编号统计
+const LEGACY_EXTENSIONS: usize = 9;
+
+// 统计的 SBI 扩展，不在表中的扩展都计入最后的 "other"。
+// 前三个有快速路径，trap.S 按位置访问，顺序不能变
+const SBI_EXTENSIONS: [(usize, &str); 12] = [
+    (0x5449_4D45, "TIME"),
+    (0x73_5049, "IPI"),
+    (0x4442_434E, "DBCN"),
+    (0x10, "BASE"),
+    (0x5246_4E43, "RFENCE"),
+    (0x48_534D, "HSM"),
+    (0x5352_5354, "SRST"),
+    (0x50_4D55, "PMU"),
+    (0x5355_5350, "SUSP"),
+    (0x0900_4C56, "LRV"),
+    (0, "legacy"),
+    (usize::MAX, "other"),
+];
+const LEGACY: usize = SBI_EXTENSIONS.len() - 2;
+const OTHER: usize = SBI_EXTENSIONS.len() - 1;
+
+/// 固件模拟的指令
+#[derive(Clone, Copy)]
+pub enum Emulated {
+    Rdtime = 0,
+    LoadHalf = 1,
+    LoadWord = 2,
+    LoadDouble = 3,
+    StoreHalf = 4,
+    StoreWord = 5,
+    StoreDouble = 6,
+}
+
+pub const EMULATED_KINDS: usize = 7;
+
+const EMULATED_NAMES: [&str; EMULATED_KINDS] = [
+    "rdtime",
+    "misaligned lh/lhu",
+    "misaligned lw/lwu",
+    "misaligned ld",
+    "misaligned sh",
+    "misaligned sw",
+    "misaligned sd",
+];
+
+impl Emulated {
+    /// 按访存宽度区分的非对齐访问模拟
+    pub fn misaligned(store: bool, unit: &MemoryUnit) -> Option<Emulated> {
+        match (store, unit) {
+            (false, MemoryUnit::HalfWord) => Some(Emulated::LoadHalf),
+            (false, MemoryUnit::Word) => Some(Emulated::LoadWord),
+            (false, MemoryUnit::DoubleWord) => Some(Emulated::LoadDouble),
+            (true, MemoryUnit::HalfWord) => Some(Emulated::StoreHalf),
+            (true, MemoryUnit::Word) => Some(Emulated::StoreWord),
+            (true, MemoryUnit::DoubleWord) => Some(Emulated::StoreDouble),
+            _ => None,
+        }
+    }
+}
+
+// 字段的顺序和 trap.S 中的 STATS_* 一致
+#[repr(C)]
+pub struct TrapStats {
+    // 快速路径进入时的 mcycle
+    entry_cycle: AtomicUsize,
+    // 在 M 态处理陷入用的周期数
+    cycles: AtomicUsize,
+    causes: [AtomicUsize; CAUSES],
+    sbi_calls: [[AtomicUsize; SBI_FUNCTIONS]; SBI_EXTENSIONS.len()],
+    emulated: [AtomicUsize; EMULATED_KINDS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const SBI_FUNCTIONS_INIT: [AtomicUsize; SBI_FUNCTIONS] = [ZERO; SBI_FUNCTIONS];
+#[allow(clippy::declare_interior_mutable_const)]
+pub const TRAP_STATS_INIT: TrapStats = TrapStats {
+    entry_cycle: ZERO,
+    cycles: ZERO,
+    causes: [ZERO; CAUSES],
+    sbi_calls: [SBI_FUNCTIONS_INIT; SBI_EXTENSIONS.len()],
+    emulated: [ZERO; EMULATED_KINDS],
+};
+
+// 只由所属的硬件线程增加，不需要原子的读改写
+fn increase(counter: &AtomicUsize, value: usize) {
+    counter.store(counter.load(Ordering::Relaxed).wrapping_add(value), Ordering::Relaxed);
+}
+
+fn cause_index(mcause: usize) -> Option<usize> {
+    let code = mcause & !INTERRUPT_BIT;
+    let index = if mcause & INTERRUPT_BIT != 0 { INTERRUPT_BASE + code } else { code };
+    if code < INTERRUPT_BASE {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+fn sbi_index(extension: usize, function: usize) -> (usize, usize) {
+    if extension < LEGACY_EXTENSIONS {
+        return (LEGACY, extension);
+    }
+    let index = SBI_EXTENSIONS[..LEGACY]
+        .iter()
+        .position(|&(eid, _)| eid == extension)
+        .unwrap_or(OTHER);
+    (index, function.min(SBI_FUNCTIONS - 1))
+}
+
+impl TrapStats {
+    /// 完整的陷入处理开始时调用，返回开始时的 mcycle
+    pub fn begin_trap(&self, mcause: usize) -> usize {
+        if let Some(index) = cause_index(mcause) {
+            increase(&self.causes[index], 1);
+        }
+        mcycle::read()
+    }
+
+    /// 完整的陷入处理结束时调用
+    pub fn end_trap(&self, start: usize) {
+        increase(&self.cycles, mcycle::read().wrapping_sub(start));
+    }
+
+    pub fn record_sbi(&self, extension: usize, function: usize) {
+        let (extension, function) = sbi_index(extension, function);
+        increase(&self.sbi_calls[extension][function], 1);
+    }
+
+    pub fn record_emulated(&self, kind: Emulated) {
+        increase(&self.emulated[kind as usize], 1);
+    }
+
+    /// mcause 为 `mcause` 的陷入次数，不支持的编号为 None
+    pub fn traps(&self, mcause: usize) -> Option<usize> {
+        cause_index(mcause).map(|index| self.causes[index].load(Ordering::Relaxed))
+    }
+
+    /// SBI 调用次数；不在统计表中的扩展合在一起统计，用其中任何一个 EID 查询都得到总数
+    pub fn sbi_calls(&self, extension: usize, function: usize) -> usize {
+        let (extension, function) = sbi_index(extension, function);
+        self.sbi_calls[extension][function].load(Ordering::Relaxed)
+    }
+
+    pub fn emulated(&self, kind: usize) -> Option<usize> {
+        self.emulated.get(kind).map(|count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    /// 清零全部计数；由其它硬件线程清零时，正在进行的陷入可能还会写回旧的值
+    pub fn reset(&self) {
+        let counters = core::iter::once(&self.cycles)
+            .chain(self.causes.iter())
+            .chain(self.sbi_calls.iter().flatten())
+            .chain(self.emulated.iter());
+        for counter in counters {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn dump(&self, hartid: usize) {
+        let traps: usize = self.causes.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+        println!(
+            "[rustsbi-stats] hart {}: {} traps, {} cycles in M-mode",
+            hartid,
+            traps,
+            self.cycles()
+        );
+        for (index, count) in self.causes.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            if index < INTERRUPT_BASE {
+                println!("[rustsbi-stats]   exception {} ({}): {}", index, exception_name(index), count);
+            } else {
+                let code = index - INTERRUPT_BASE;
+                println!("[rustsbi-stats]   interrupt {} ({}): {}", code, interrupt_name(code), count);
+            }
+        }
+        for (&(_, name), functions) in SBI_EXTENSIONS.iter().zip(self.sbi_calls.iter()) {
+            for (function, count) in functions.iter().enumerate() {
+                let count = count.load(Ordering::Relaxed);
+                if count == 0 {
+                    continue;
+                }
+                let more = if function == SBI_FUNCTIONS - 1 { " and above" } else { "" };
+                println!("[rustsbi-stats]   SBI {} fid {}{}: {}", name, function, more, count);
+            }
+        }
+        for (name, count) in EMULATED_NAMES.iter().zip(self.emulated.iter()) {
+            let count = count.load(Ordering::Relaxed);
+            if count != 0 {
+                println!("[rustsbi-stats]   emulated {}: {}", name, count);
+            }
+        }
+    }
+}
+
+/// 把启动过的硬件线程的统计打印到控制台，只列出不为 0 的计数
+pub fn dump() {
+    for hartid in 0..=hart::max_hart_id() {
+        if let Some(context) = hart::context(hartid) {
+            if context.started() {
+                context.stats().dump(hartid);
+            }
+        }
+    }
+}
+
+fn exception_name(code: usize) -> &'static str {
+    match code {
+        0 => "instruction misaligned",
+        1 => "instruction fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load misaligned",
+        5 => "load fault",
+        6 => "store misaligned",
+        7 => "store fault",
+        8 => "user ecall",
+        9 => "supervisor ecall",
+        11 => "machine ecall",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store page fault",
+        _ => "reserved",
+    }
+}
+
+fn interrupt_name(code: usize) -> &'static str {
+    match code {
+        1 => "supervisor software",
+        3 => "machine software",
+        5 => "supervisor timer",
+        7 => "machine timer",
+        9 => "supervisor external",
+        11 => "machine external",
+        _ => "reserved",
+    }
+}
@@ -1,7 +1,10 @@
-use core::arch::global_asm;
+use core::arch::{asm, global_asm};
 
+use crate::ecall::{self, pmu::{self, FirmwareEvent}};
 use crate::hal;
+use crate::hart;
 use crate::misaligned;
+use crate::stats::Emulated;
 
 global_asm!(include_str!("rv64.S"));
 
@@ -39,6 +42,16 @@ struct TrapFrame {
     s10: usize,
     s11: usize,
     satp: usize,
+    // 被打断的 sp 和 tp
+    sp: usize,
+    tp: usize,
+    // 陷入时的 CSR ，嵌套陷入覆盖之后用来恢复外层的状态
+    mepc: usize,
+    mstatus: usize,
+    mcause: usize,
+    mtval: usize,
+    // 外层陷入的陷入帧，不是嵌套陷入时为空
+    prev: *mut TrapFrame,
 }
 
 impl TrapFrame {
@@ -46,6 +59,8 @@ impl TrapFrame {
     fn set_register_xi(&mut self, i: u8, data: usize) {
         match i {
             1 => self.ra = data,
+            2 => self.sp = data,
+            4 => self.tp = data,
             10 => self.a0 = data,
             11 => self.a1 = data,
             12 => self.a2 = data,
@@ -97,6 +112,8 @@ impl TrapFrame {
         match i {
             0 => 0,
             1 => self.ra,
+            2 => self.sp,
+            4 => self.tp,
             10 => self.a0,
             11 => self.a1,
             12 => self.a2,
@@ -147,10 +164,10 @@ impl TrapFrame {
 pub fn init_trap() {
     use riscv::register::mtvec::{self, TrapMode};
     extern "C" {
-        fn _start_trap();
+        static _trap_vector: u8;
     }
     unsafe {
-        mtvec::write(_start_trap as usize, TrapMode::Direct);
+        mtvec::write(&_trap_vector as *const _ as usize, TrapMode::Vectored);
     }
 }
 
@@ -178,6 +195,7 @@ pub fn delegate_trap() {
         medeleg::clear_instruction_fault();
         // medeleg::set_load_fault();
         // medeleg::set_store_fault();
+        // 固件注册了自己的外部中断源后由 Plic::enable 打开
         mie::clear_mext();
         mie::clear_mtimer();
         // 不打开mie::set_mtimer
@@ -187,32 +205,57 @@ pub fn delegate_trap() {
 
 #[export_name = "_start_trap_rust"]
 extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
+    // 处理陷入的过程中又发生了陷入
+    if hart::current().trap_depth() > 1 {
+        return nested_trap(trap_frame);
+    }
+    let stats = hart::current().stats();
+    let start = stats.begin_trap(riscv::register::mcause::read().bits());
+    handle_trap(trap_frame);
+    stats.end_trap(start);
+}
+
+fn handle_trap(trap_frame: &mut TrapFrame) {
     use misaligned::MemoryUnit;
     use riscv::register::{
         mcause::{self, Exception, Interrupt, Trap},
-        mepc, mhartid, mie, mip,
-        mstatus::{self, MPP, SPP},
-        mtval, scause, sepc, stval, stvec,
+        mepc, mie, mip,
+        mstatus::{self, MPP},
+        mtval, scause,
     };
-    use rustsbi::println;
+    // 陷入处理也运行在这个栈上，栈溢出时尽早停下，不要破坏相邻硬件线程的栈和堆
+    if !hart::current().stack_guard_intact() {
+        panic!("M-mode stack overflow, trap frame at {:p}", trap_frame as *const _);
+    }
+    // 控制台输出只写进缓冲区，借每次陷入把它发出去
+    hal::Uartlite::poll();
     let cause = mcause::read().cause();
     match cause {
         Trap::Exception(Exception::SupervisorEnvCall) => {
             let params = [trap_frame.a0, trap_frame.a1, trap_frame.a2, trap_frame.a3, trap_frame.a4, trap_frame.a5];
-            // Call RustSBI procedure
-            let ans = rustsbi::ecall(trap_frame.a7, trap_frame.a6, params);
+            // Skip ecall instruction；先跳过，处理函数可以改写返回地址
+            mepc::write(mepc::read().wrapping_add(4));
+            // 固件自己实现的扩展优先，其余的交给 RustSBI
+            let ans = ecall::handle(trap_frame.a7, trap_frame.a6, params)
+                .unwrap_or_else(|| rustsbi::ecall(trap_frame.a7, trap_frame.a6, params));
             // Return the return value to TrapFrame
             trap_frame.a0 = ans.error;
             trap_frame.a1 = ans.value;
-            // Skip ecall instruction
-            mepc::write(mepc::read().wrapping_add(4));
         }
         Trap::Interrupt(Interrupt::MachineSoft) => {
-            println!("[rustsbi trap handler] Machine Software Interrupt! mhartid: {:016x?}", mhartid::read());
+            pmu::record(FirmwareEvent::IpiReceived);
+            // 先清除 msip 再取消息，清除之后到达的消息会再触发一次中断
+            let context = hart::current();
+            hal::clint().clear_soft(context.hartid());
+            let messages = context.take_ipi();
             // 机器软件中断返回给S层
-            unsafe {
-                mip::set_ssoft();
-                mie::clear_msoft();
+            if messages & hart::ipi::SSIP != 0 {
+                unsafe { mip::set_ssoft() };
+            }
+        }
+        Trap::Interrupt(Interrupt::MachineExternal) => {
+            if let Some(plic) = hal::plic() {
+                plic.handle();
             }
         }
         Trap::Interrupt(Interrupt::MachineTimer) => {
@@ -229,34 +272,17 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
             let ins = unsafe { misaligned::load_vaddr(vaddr, MemoryUnit::Word, false) };
             if ins & 0xFFFFF07F == 0xC0102073 {
                 // rdtime
+                pmu::record(FirmwareEvent::IllegalInstruction);
+                hart::current().stats().record_emulated(Emulated::Rdtime);
                 let rd = ((ins >> 7) & 0b1_1111) as u8;
-                // todo: one instance only
-                let clint = hal::Clint::new(0x2000000 as *mut u8);
-                let time_usize = clint.get_mtime() as usize;
+                let time_usize = hal::clint().get_mtime() as usize;
                 trap_frame.set_register_xi(rd, time_usize);
                 mepc::write(mepc::read().wrapping_add(4)); // 跳过指令
             } else
             if mstatus::read().mpp() != MPP::Machine {
                 // 出现非法指令异常，转发到S特权层
                 // invalid instruction, can't emulate, raise to supervisor
-                unsafe {
-                    // 设置S层异常原因为：非法指令
-                    scause::set(scause::Trap::Exception(scause::Exception::IllegalInstruction));
-                    // 填写异常指令的指令内容
-                    stval::write(mtval::read());
-                    // 填写S层需要返回到的地址，这里的mepc会被随后的代码覆盖掉
-                    sepc::write(mepc::read());
-                    // 设置中断位
-                    mstatus::set_mpp(MPP::Supervisor);
-                    mstatus::set_spp(SPP::Supervisor);
-                    if mstatus::read().sie() {
-                        mstatus::set_spie()
-                    }
-                    mstatus::clear_sie();
-                    // 设置返回地址，返回到S层
-                    // 注意，无论是Direct还是Vectored模式，所有异常的向量偏移都是0，不需要处理中断向量，跳转到入口地址即可
-                    mepc::write(stvec::read().address());
-                };
+                unsafe { forward_to_supervisor(scause::Exception::IllegalInstruction) };
             } else {
                 // 真·非法指令异常，是M层出现的
                 #[cfg(target_pointer_width = "64")]
@@ -265,7 +291,16 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
                 panic!("invalid instruction, mepc: {:08x?}, instruction: {:08x?}", mepc::read(), ins);
             }
         }
+        Trap::Exception(Exception::LoadFault) => {
+            pmu::record(FirmwareEvent::AccessLoad);
+            access_fault("load", scause::Exception::LoadFault);
+        }
+        Trap::Exception(Exception::StoreFault) => {
+            pmu::record(FirmwareEvent::AccessStore);
+            access_fault("store", scause::Exception::StoreFault);
+        }
         Trap::Exception(Exception::LoadMisaligned) => {
+            pmu::record(FirmwareEvent::MisalignedLoad);
             let ins_vaddr = mepc::read();
             let ins = unsafe {
                 misaligned::load_vaddr(ins_vaddr, MemoryUnit::HalfWord, false)
@@ -281,6 +316,7 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
                     let mem_unit = MemoryUnit::from((ins >> 12) & 0b11);
                     let load_vaddr = mtval::read();
                     let signed = ((ins >> 14) & 1) == 0;
+                    record_misaligned(false, &mem_unit);
                     let load_value = unsafe { misaligned::load_vaddr(load_vaddr, mem_unit, signed)};
                     trap_frame.set_register_xi(rd, load_value);
                     // println!("[rustsbi trap handler] Load misaligned! epc: {:016x?}, ins: {:016x}, addr: {:016x}", ins_vaddr , ins, load_vaddr);
@@ -293,6 +329,7 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
                     let mem_unit = MemoryUnit::from((ins >> 13) & 0b11); // 只考虑 RV64IC
                     let load_vaddr = mtval::read();
                     let signed = true;
+                    record_misaligned(false, &mem_unit);
                     let load_value = unsafe { misaligned::load_vaddr(load_vaddr, mem_unit, signed)};
                     trap_frame.set_register_xi(rd, load_value);
                     // println!("[rustsbi trap handler] Load misaligned! epc: {:016x?}, ins: {:016x}, addr: {:016x}", ins_vaddr , ins, load_vaddr);
@@ -306,6 +343,7 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
                     let mem_unit = MemoryUnit::from((ins >> 13) & 0b11);
                     let load_vaddr = mtval::read();
                     let signed = true;
+                    record_misaligned(false, &mem_unit);
                     let load_value = unsafe { misaligned::load_vaddr(load_vaddr, mem_unit, signed)};
                     trap_frame.set_register_xic(rd, load_value);
                     // println!("[rustsbi trap handler] Load misaligned! epc: {:016x?}, ins: {:016x}, addr: {:016x}", ins_vaddr , ins, load_vaddr);
@@ -318,6 +356,7 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
             }
         }
         Trap::Exception(Exception::StoreMisaligned) => {
+            pmu::record(FirmwareEvent::MisalignedStore);
             let ins_vaddr = mepc::read();
             let ins = unsafe {
                 misaligned::load_vaddr(ins_vaddr, MemoryUnit::HalfWord, false)
@@ -333,6 +372,7 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
                     let store_value = trap_frame.get_register_xi(rs);
                     let mem_unit = MemoryUnit::from((ins >> 12) & 0b11);
                     let store_vaddr = mtval::read();
+                    record_misaligned(true, &mem_unit);
                     unsafe { misaligned::store_vaddr(store_vaddr, mem_unit, store_value)};
                     // println!("[rustsbi trap handler] Store misaligned! epc: {:016x?}, ins: {:016x}, addr: {:016x}", ins_vaddr , ins, store_vaddr);
                     // println!("[rustsbi trap handler] (uncompressed) rs: {:?} value: {:016x}", rs,store_value);
@@ -344,6 +384,7 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
                     let store_value = trap_frame.get_register_xi(rs);
                     let mem_unit = MemoryUnit::from((ins >> 13) & 0b11); // 只考虑 RV64IC
                     let store_vaddr = mtval::read();
+                    record_misaligned(true, &mem_unit);
                     unsafe { misaligned::store_vaddr(store_vaddr, mem_unit, store_value)};
                     // println!("[rustsbi trap handler] Store misaligned! epc: {:016x?}, ins: {:016x}, addr: {:016x}", ins_vaddr , ins, store_vaddr);
                     // println!("[rustsbi trap handler] (c, sp based) rs: {:?} value: {:016x}", rs,store_value);
@@ -356,6 +397,7 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
                     let store_value = trap_frame.get_register_xic(rs);
                     let mem_unit = MemoryUnit::from((ins >> 13) & 0b11);
                     let store_vaddr = mtval::read();
+                    record_misaligned(true, &mem_unit);
                     unsafe { misaligned::store_vaddr(store_vaddr, mem_unit, store_value)};
                     // println!("[rustsbi trap handler] Store misaligned! epc: {:016x?}, ins: {:016x}, addr: {:016x}", ins_vaddr , ins, store_vaddr);
                     // println!("[rustsbi trap handler] (compressed) rs: {:?} value: {:016x}", rs,store_value);
@@ -389,3 +431,134 @@ extern "C" fn start_trap_rust(trap_frame: &mut TrapFrame) {
         ),
     }
 }
+
+fn record_misaligned(store: bool, unit: &misaligned::MemoryUnit) {
+    if let Some(kind) = Emulated::misaligned(store, unit) {
+        hart::current().stats().record_emulated(kind);
+    }
+}
+
+// 访存异常：打印总线错误单元的诊断信息；S/U 态产生的转发给 S 态，由内核处理，M 态产生的无法恢复
+fn access_fault(kind: &str, exception: riscv::register::scause::Exception) {
+    use riscv::register::{mepc, mstatus::{self, MPP}, mtval, satp};
+    let mpp = mstatus::read().mpp();
+    // M 态在没有设置 MPRV 时、其它特权级在没有开启分页时，mtval 是物理地址
+    let physical = match mpp {
+        MPP::Machine => !mstatus::read().mprv(),
+        _ => satp::read().mode() == satp::Mode::Bare,
+    };
+    hal::bus_error().report_fault(kind, mtval::read(), physical, mepc::read());
+    if mpp != MPP::Machine {
+        unsafe { forward_to_supervisor(exception) };
+    } else {
+        panic!(
+            "{} access fault in M mode, mepc: {:#x}, mtval: {:#x}",
+            kind,
+            mepc::read(),
+            mtval::read()
+        );
+    }
+}
+
+// 把当前的异常转发到 S 态：填写 scause、stval 和 sepc ，模拟一次进入 S 态的陷入，返回到 stvec
+unsafe fn forward_to_supervisor(exception: riscv::register::scause::Exception) {
+    use riscv::register::{
+        mepc,
+        mstatus::{self, MPP, SPP},
+        mtval, scause, sepc, stval, stvec,
+    };
+    // 设置S层异常原因
+    scause::set(scause::Trap::Exception(exception));
+    // 填写异常的附加信息（指令内容或出错地址）
+    stval::write(mtval::read());
+    // 填写S层需要返回到的地址，这里的mepc会被随后的代码覆盖掉
+    sepc::write(mepc::read());
+    // 设置中断位：SPP 按陷入前的特权级设置，U 态的异常交给内核后 sret 仍然回到 U 态
+    let spp = match mstatus::read().mpp() {
+        MPP::User => SPP::User,
+        _ => SPP::Supervisor,
+    };
+    mstatus::set_mpp(MPP::Supervisor);
+    mstatus::set_spp(spp);
+    if mstatus::read().sie() {
+        mstatus::set_spie()
+    } else {
+        asm!("csrc mstatus, {0}", in(reg) MSTATUS_SPIE);
+    }
+    mstatus::clear_sie();
+    // 设置返回地址，返回到S层
+    // 注意，无论是Direct还是Vectored模式，所有异常的向量偏移都是0，不需要处理中断向量，跳转到入口地址即可
+    mepc::write(stvec::read().address());
+}
+
+// 处理陷入的过程中发生的陷入。
+//
+// 固件代替 S/U 态访问它的内存时（mstatus.MPRV 置位）发生的访存异常可以恢复：放弃外层的处理，
+// 把异常转发给 S 态，就像是被模拟的指令自己出了错；其它情况打印两层陷入的信息后停机
+fn nested_trap(trap_frame: &mut TrapFrame) {
+    use riscv::register::{
+        mcause::{self, Exception, Trap},
+        mepc, mstatus, mtval, scause,
+    };
+    // 第三层：打印本身也出错了，只能停下来
+    if hart::current().trap_depth() > 2 {
+        loop {
+            unsafe { riscv::asm::wfi() };
+        }
+    }
+    let outer = unsafe { &mut *trap_frame.prev };
+    let cause = mcause::read().cause();
+    let addr = mtval::read();
+    // 外层陷入来自 S/U 态，并且正在用 MPRV 访问 S 态的内存
+    let recoverable = mstatus::read().mprv() && outer.mstatus & MSTATUS_MPP != MSTATUS_MPP;
+    // 取指令时出错应当报告为取指异常
+    let fetch = addr.wrapping_sub(outer.mepc) < 4;
+    let exception = match cause {
+        Trap::Exception(Exception::LoadPageFault) if fetch => scause::Exception::InstructionPageFault,
+        Trap::Exception(Exception::LoadFault) if fetch => scause::Exception::InstructionFault,
+        Trap::Exception(Exception::LoadPageFault) => scause::Exception::LoadPageFault,
+        Trap::Exception(Exception::LoadFault) => scause::Exception::LoadFault,
+        Trap::Exception(Exception::StorePageFault) => scause::Exception::StorePageFault,
+        Trap::Exception(Exception::StoreFault) => scause::Exception::StoreFault,
+        _ => scause::Exception::Unknown,
+    };
+    if recoverable && exception != scause::Exception::Unknown {
+        unsafe { unwind_to_supervisor(outer, exception, addr) };
+        return;
+    }
+    panic!(
+        "nested trap! mcause: {:?}, mepc: {:#x}, mtval: {:#x}; while handling mcause: {:#x}, mepc: {:#x}, mtval: {:#x}, mstatus: {:#x}",
+        cause,
+        mepc::read(),
+        addr,
+        outer.mcause,
+        outer.mepc,
+        outer.mtval,
+        outer.mstatus
+    );
+}
+
+const MSTATUS_SIE: usize = 1 << 1;
+const MSTATUS_SPIE: usize = 1 << 5;
+const MSTATUS_SPP: usize = 1 << 8;
+const MSTATUS_MPP: usize = 3 << 11;
+const MSTATUS_MPP_S: usize = 1 << 11;
+
+// 和 forward_to_supervisor 相同，但修改的是外层陷入帧中的 mepc 和 mstatus ，由 trap.S 按它们返回
+unsafe fn unwind_to_supervisor(outer: &mut TrapFrame, exception: riscv::register::scause::Exception, addr: usize) {
+    use riscv::register::{scause, sepc, stval, stvec};
+    scause::set(scause::Trap::Exception(exception));
+    stval::write(addr);
+    sepc::write(outer.mepc);
+    let mut mstatus = outer.mstatus & !(MSTATUS_MPP | MSTATUS_SPP | MSTATUS_SPIE);
+    // 外层陷入来自 U 态时 SPP 保持为 0 ，sret 回到 U 态
+    if outer.mstatus & MSTATUS_MPP == MSTATUS_MPP_S {
+        mstatus |= MSTATUS_SPP;
+    }
+    mstatus |= MSTATUS_MPP_S;
+    if mstatus & MSTATUS_SIE != 0 {
+        mstatus |= MSTATUS_SPIE;
+    }
+    outer.mstatus = mstatus & !MSTATUS_SIE;
+    outer.mepc = stvec::read().address();
+}
@@ -0,0 +1,315 @@
+// XMODEM-1K / YMODEM 接收端，只支持 CRC 校验模式
+// Ref: http://pauillac.inria.fr/~doligez/zmodem/ymodem.txt
+//
+// 协议部分只依赖 embedded_hal 的串口 trait 和一个时钟函数，可以在主机上对着模拟的串口对端测试，
+// 测试在 tests/ 中
+#![no_std]
+
+use embedded_hal::serial::{Read, Write};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC: u8 = b'C';
+
+// 等待发送方开始传输时，每秒发一次'C'，最多等这么多次
+const START_RETRIES: usize = 60;
+// 单个数据包连续出错的最多次数
+const MAX_ERRORS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// 发送方一直没有开始传输
+    Timeout,
+    /// 发送方取消了传输
+    Cancelled,
+    /// 连续出错太多次
+    TooManyErrors,
+    /// 数据包序号错乱
+    OutOfSequence,
+    /// 接收的数据超出了缓冲区
+    Overflow,
+    /// YMODEM 批量传输了多个文件
+    MultipleFiles,
+}
+
+/// 接收结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    /// 写入缓冲区的字节数；XMODEM 没有文件长度，会包含末尾的填充字节
+    pub len: usize,
+    /// 是否通过 YMODEM 接收
+    pub ymodem: bool,
+}
+
+enum Packet {
+    Data { seq: u8, len: usize },
+    End,
+}
+
+pub struct Receiver<'a, S, F> {
+    serial: &'a mut S,
+    now: F,
+    ticks_per_second: u64,
+    block: [u8; 1024],
+}
+
+impl<'a, S, F> Receiver<'a, S, F>
+where
+    S: Read<u8> + Write<u8>,
+    F: Fn() -> u64,
+{
+    /// `now` 返回当前时刻，`ticks_per_second` 为它每秒增加的计数
+    pub fn new(serial: &'a mut S, now: F, ticks_per_second: u64) -> Self {
+        Self {
+            serial,
+            now,
+            ticks_per_second,
+            block: [0; 1024],
+        }
+    }
+
+    /// 接收一个文件写入 `buf`；发送方用 XMODEM-1K（或 XMODEM-CRC）和 YMODEM 都可以
+    pub fn receive(&mut self, buf: &mut [u8]) -> Result<Received, Error> {
+        let mut offset = 0;
+        let mut expected: u8 = 1;
+        let mut ymodem = false;
+        let mut file_size = None;
+        let mut eot_count = 0;
+
+        let mut packet = self.start()?;
+        loop {
+            let (seq, len) = match packet {
+                Packet::Data { seq, len } => (seq, len),
+                Packet::End => {
+                    if !ymodem {
+                        self.send(ACK);
+                        break;
+                    }
+                    // YMODEM 的第一个 EOT 要回 NAK，发送方会再发一个
+                    eot_count += 1;
+                    if eot_count == 1 {
+                        self.send(NAK);
+                        packet = self.next_packet()?;
+                        continue;
+                    }
+                    self.send(ACK);
+                    // 结束批量传输：对方会发一个文件名为空的 0 号包
+                    self.finish_batch()?;
+                    break;
+                }
+            };
+            eot_count = 0;
+            if seq == 0 && expected == 1 && offset == 0 && !ymodem {
+                // YMODEM 的文件头：文件名\0文件长度 ...
+                match parse_header(&self.block[..len]) {
+                    Some(size) => {
+                        ymodem = true;
+                        file_size = size;
+                        if let Some(size) = size {
+                            if size > buf.len() {
+                                self.cancel();
+                                return Err(Error::Overflow);
+                            }
+                        }
+                        self.send(ACK);
+                        packet = self.start()?;
+                        continue;
+                    }
+                    None => {
+                        // 空文件名，没有文件要传
+                        self.send(ACK);
+                        return Ok(Received { len: 0, ymodem: true });
+                    }
+                }
+            }
+            if seq == expected {
+                // YMODEM 知道文件长度，最后一包的填充部分不用写入
+                let needed = match file_size {
+                    Some(size) => size.saturating_sub(offset).min(len),
+                    None => len,
+                };
+                if offset + needed > buf.len() {
+                    self.cancel();
+                    return Err(Error::Overflow);
+                }
+                buf[offset..offset + needed].copy_from_slice(&self.block[..needed]);
+                offset += needed;
+                expected = expected.wrapping_add(1);
+                self.send(ACK);
+            } else if seq == expected.wrapping_sub(1) {
+                // 对方没收到上一个 ACK，重发了一遍
+                self.send(ACK);
+            } else {
+                self.cancel();
+                return Err(Error::OutOfSequence);
+            }
+            packet = self.next_packet()?;
+        }
+        Ok(Received { len: offset, ymodem })
+    }
+
+    // 不断发送'C'请求 CRC 模式，直到收到第一个包
+    fn start(&mut self) -> Result<Packet, Error> {
+        for _ in 0..START_RETRIES {
+            self.send(CRC);
+            match self.read_packet() {
+                Ok(packet) => return Ok(packet),
+                Err(PacketError::Timeout) => continue,
+                Err(PacketError::Cancelled) => return Err(Error::Cancelled),
+                Err(PacketError::Corrupted) => {
+                    self.purge();
+                    return self.retry();
+                }
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn next_packet(&mut self) -> Result<Packet, Error> {
+        match self.read_packet() {
+            Ok(packet) => Ok(packet),
+            Err(PacketError::Cancelled) => Err(Error::Cancelled),
+            Err(_) => {
+                self.purge();
+                self.retry()
+            }
+        }
+    }
+
+    // 出错后回 NAK 让对方重传
+    fn retry(&mut self) -> Result<Packet, Error> {
+        for _ in 0..MAX_ERRORS {
+            self.send(NAK);
+            match self.read_packet() {
+                Ok(packet) => return Ok(packet),
+                Err(PacketError::Cancelled) => return Err(Error::Cancelled),
+                Err(_) => self.purge(),
+            }
+        }
+        self.cancel();
+        Err(Error::TooManyErrors)
+    }
+
+    fn finish_batch(&mut self) -> Result<(), Error> {
+        self.send(CRC);
+        let packet = match self.read_packet() {
+            Ok(packet) => packet,
+            Err(PacketError::Cancelled) => return Err(Error::Cancelled),
+            Err(_) => {
+                self.purge();
+                self.retry()?
+            }
+        };
+        match packet {
+            Packet::Data { seq: 0, len } if parse_header(&self.block[..len]).is_none() => {
+                self.send(ACK);
+                Ok(())
+            }
+            _ => {
+                self.cancel();
+                Err(Error::MultipleFiles)
+            }
+        }
+    }
+
+    fn read_packet(&mut self) -> Result<Packet, PacketError> {
+        let len = match self.read_byte(self.ticks_per_second)? {
+            SOH => 128,
+            STX => 1024,
+            EOT => return Ok(Packet::End),
+            CAN => {
+                // 连续两个 CAN 才算取消
+                match self.read_byte(self.ticks_per_second)? {
+                    CAN => return Err(PacketError::Cancelled),
+                    _ => return Err(PacketError::Corrupted),
+                }
+            }
+            _ => return Err(PacketError::Corrupted),
+        };
+        let seq = self.read_byte(self.ticks_per_second)?;
+        let seq_complement = self.read_byte(self.ticks_per_second)?;
+        for i in 0..len {
+            self.block[i] = self.read_byte(self.ticks_per_second)?;
+        }
+        let crc_hi = self.read_byte(self.ticks_per_second)? as u16;
+        let crc_lo = self.read_byte(self.ticks_per_second)? as u16;
+        if seq != !seq_complement || crc16(&self.block[..len]) != (crc_hi << 8 | crc_lo) {
+            return Err(PacketError::Corrupted);
+        }
+        Ok(Packet::Data { seq, len })
+    }
+
+    fn read_byte(&mut self, timeout: u64) -> Result<u8, PacketError> {
+        let start = (self.now)();
+        loop {
+            match self.serial.read() {
+                Ok(byte) => return Ok(byte),
+                // 串口报错时这个包反正是坏了，等重传
+                Err(nb::Error::Other(_)) => return Err(PacketError::Corrupted),
+                Err(nb::Error::WouldBlock) => {}
+            }
+            if (self.now)().wrapping_sub(start) >= timeout {
+                return Err(PacketError::Timeout);
+            }
+        }
+    }
+
+    // 丢弃线路上剩余的数据，直到安静一秒
+    fn purge(&mut self) {
+        while let Ok(_) | Err(PacketError::Corrupted) = self.read_byte(self.ticks_per_second) {}
+    }
+
+    fn cancel(&mut self) {
+        self.send(CAN);
+        self.send(CAN);
+    }
+
+    fn send(&mut self, byte: u8) {
+        let _ = nb::block!(self.serial.write(byte));
+    }
+}
+
+enum PacketError {
+    Timeout,
+    Cancelled,
+    Corrupted,
+}
+
+// 解析 YMODEM 0 号包，返回文件长度；文件名为空时返回 None
+fn parse_header(block: &[u8]) -> Option<Option<usize>> {
+    let name_end = block.iter().position(|&b| b == 0)?;
+    if name_end == 0 {
+        return None;
+    }
+    let size = block[name_end + 1..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .try_fold(None, |acc: Option<usize>, &b| {
+            acc.unwrap_or(0)
+                .checked_mul(10)
+                .and_then(|n| n.checked_add((b - b'0') as usize))
+                .map(Some)
+        })
+        .flatten();
+    Some(size)
+}
+
+// CRC-16/XMODEM：多项式 0x1021，初值 0
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
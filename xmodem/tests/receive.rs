@@ -0,0 +1,305 @@
+// 对着模拟的发送方测试接收端：发送方按脚本应答，接收端每发出一个字节，
+// 如果它是脚本中下一步等待的字节，就把这一步的回应放进接收端的输入
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+use embedded_hal::serial::{Read, Write};
+use xmodem::{Error, Received, Receiver};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const CRC: u8 = b'C';
+
+const TICKS_PER_SECOND: u64 = 1000;
+// 没有数据可读时时钟前进的量
+const IDLE_TICKS: u64 = 10;
+
+struct Peer {
+    clock: Rc<Cell<u64>>,
+    // 接收端还没有读走的字节
+    input: VecDeque<u8>,
+    // 接收端发出的全部字节
+    output: Vec<u8>,
+    script: VecDeque<(u8, Vec<u8>)>,
+}
+
+impl Peer {
+    fn new(script: Vec<(u8, Vec<u8>)>) -> Self {
+        Self {
+            clock: Rc::new(Cell::new(0)),
+            input: VecDeque::new(),
+            output: Vec::new(),
+            script: script.into(),
+        }
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> Result<Received, Error> {
+        let clock = self.clock.clone();
+        Receiver::new(self, move || clock.get(), TICKS_PER_SECOND).receive(buf)
+    }
+
+    fn count(&self, byte: u8) -> usize {
+        self.output.iter().filter(|&&b| b == byte).count()
+    }
+}
+
+impl Read<u8> for Peer {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        match self.input.pop_front() {
+            Some(byte) => Ok(byte),
+            None => {
+                self.clock.set(self.clock.get() + IDLE_TICKS);
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+}
+
+impl Write<u8> for Peer {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        self.output.push(byte);
+        if self.script.front().map(|&(expected, _)| expected) == Some(byte) {
+            let (_, reply) = self.script.pop_front().unwrap();
+            self.input.extend(reply);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// 一个数据包；data 不够长时用 0x1a 填充到 128 或 1024 字节
+fn packet(seq: u8, data: &[u8], long: bool) -> Vec<u8> {
+    let len = if long { 1024 } else { 128 };
+    let mut block = data.to_vec();
+    block.resize(len, 0x1a);
+    let crc = crc16(&block);
+    let mut packet = vec![if long { STX } else { SOH }, seq, !seq];
+    packet.extend(block);
+    packet.extend(&crc.to_be_bytes());
+    packet
+}
+
+// YMODEM 0 号包，128 字节，剩余部分填 0
+fn header(name: &str, size: Option<usize>) -> Vec<u8> {
+    let mut block = name.as_bytes().to_vec();
+    block.push(0);
+    if let Some(size) = size {
+        block.extend(format!("{} 14252725061 100644", size).as_bytes());
+    }
+    block.resize(128, 0);
+    packet(0, &block, false)
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[test]
+fn xmodem_1k_and_crc_blocks() {
+    let data = pattern(1024 + 128);
+    let mut peer = Peer::new(vec![
+        (CRC, packet(1, &data[..1024], true)),
+        (ACK, packet(2, &data[1024..], false)),
+        (ACK, vec![EOT]),
+    ]);
+    let mut buf = [0; 4096];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 1152, ymodem: false });
+    assert_eq!(&buf[..1152], &data[..]);
+    assert_eq!(peer.output, vec![CRC, ACK, ACK, ACK]);
+}
+
+#[test]
+fn xmodem_keeps_padding_of_last_block() {
+    let data = pattern(100);
+    let mut peer = Peer::new(vec![(CRC, packet(1, &data, false)), (ACK, vec![EOT])]);
+    let mut buf = [0; 256];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 128, ymodem: false });
+    assert_eq!(&buf[..100], &data[..]);
+    assert!(buf[100..128].iter().all(|&b| b == 0x1a));
+}
+
+#[test]
+fn ymodem_header_trims_to_file_size() {
+    let data = pattern(1500);
+    let mut peer = Peer::new(vec![
+        (CRC, header("kernel.bin", Some(1500))),
+        // 0 号包的 ACK 之后接收端再发 'C' 才开始传数据
+        (CRC, packet(1, &data[..1024], true)),
+        (ACK, packet(2, &data[1024..], true)),
+        (ACK, vec![EOT]),
+        (NAK, vec![EOT]),
+        (CRC, header("", None)),
+    ]);
+    let mut buf = [0xff; 4096];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 1500, ymodem: true });
+    assert_eq!(&buf[..1500], &data[..]);
+    // 最后一包的填充没有写进缓冲区
+    assert!(buf[1500..].iter().all(|&b| b == 0xff));
+    assert_eq!(peer.output, vec![CRC, ACK, CRC, ACK, ACK, NAK, ACK, CRC, ACK]);
+}
+
+#[test]
+fn ymodem_header_without_size() {
+    let data = pattern(128);
+    let mut peer = Peer::new(vec![
+        (CRC, header("kernel.bin", None)),
+        (CRC, packet(1, &data, false)),
+        (ACK, vec![EOT]),
+        (NAK, vec![EOT]),
+        (CRC, header("", None)),
+    ]);
+    let mut buf = [0; 256];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 128, ymodem: true });
+    assert_eq!(&buf[..128], &data[..]);
+}
+
+#[test]
+fn ymodem_empty_batch() {
+    let mut peer = Peer::new(vec![(CRC, header("", None))]);
+    let mut buf = [0; 128];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 0, ymodem: true });
+    assert_eq!(peer.output, vec![CRC, ACK]);
+}
+
+#[test]
+fn ymodem_file_larger_than_buffer() {
+    let mut peer = Peer::new(vec![(CRC, header("kernel.bin", Some(4096)))]);
+    let mut buf = [0; 1024];
+    assert_eq!(peer.receive(&mut buf), Err(Error::Overflow));
+    assert!(peer.output.ends_with(&[CAN, CAN]));
+}
+
+#[test]
+fn bad_crc_is_retransmitted() {
+    let data = pattern(256);
+    let mut corrupted = packet(2, &data[128..], false);
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    // 第二个包校验错，回 NAK 后重传
+    let mut peer = Peer::new(vec![
+        (CRC, packet(1, &data[..128], false)),
+        (ACK, corrupted),
+        (NAK, packet(2, &data[128..], false)),
+        (ACK, vec![EOT]),
+    ]);
+    let mut buf = [0; 256];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 256, ymodem: false });
+    assert_eq!(&buf[..], &data[..]);
+    assert_eq!(peer.output, vec![CRC, ACK, NAK, ACK, ACK]);
+}
+
+#[test]
+fn bad_sequence_complement_is_retransmitted() {
+    let data = pattern(128);
+    let mut corrupted = packet(1, &data, false);
+    corrupted[2] = 0;
+    let mut peer = Peer::new(vec![
+        (CRC, corrupted),
+        (NAK, packet(1, &data, false)),
+        (ACK, vec![EOT]),
+    ]);
+    let mut buf = [0; 128];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 128, ymodem: false });
+    assert_eq!(&buf[..], &data[..]);
+}
+
+#[test]
+fn too_many_errors() {
+    let mut corrupted = packet(1, &pattern(128), false);
+    corrupted[3] ^= 1;
+    let mut script = vec![(CRC, corrupted.clone())];
+    script.extend((0..20).map(|_| (NAK, corrupted.clone())));
+    let mut peer = Peer::new(script);
+    let mut buf = [0; 128];
+    assert_eq!(peer.receive(&mut buf), Err(Error::TooManyErrors));
+    assert!(peer.output.ends_with(&[CAN, CAN]));
+}
+
+#[test]
+fn duplicate_block_is_acknowledged_and_dropped() {
+    let data = pattern(256);
+    let mut peer = Peer::new(vec![
+        (CRC, packet(1, &data[..128], false)),
+        // 发送方没收到 ACK，重发了 1 号包
+        (ACK, packet(1, &data[..128], false)),
+        (ACK, packet(2, &data[128..], false)),
+        (ACK, vec![EOT]),
+    ]);
+    let mut buf = [0; 512];
+    let received = peer.receive(&mut buf).unwrap();
+    assert_eq!(received, Received { len: 256, ymodem: false });
+    assert_eq!(&buf[..256], &data[..]);
+    assert_eq!(peer.output, vec![CRC, ACK, ACK, ACK, ACK]);
+}
+
+#[test]
+fn out_of_sequence_block_cancels() {
+    let data = pattern(128);
+    let mut peer = Peer::new(vec![
+        (CRC, packet(1, &data, false)),
+        (ACK, packet(3, &data, false)),
+    ]);
+    let mut buf = [0; 512];
+    assert_eq!(peer.receive(&mut buf), Err(Error::OutOfSequence));
+    assert!(peer.output.ends_with(&[CAN, CAN]));
+}
+
+#[test]
+fn sender_cancels() {
+    let data = pattern(128);
+    let mut peer = Peer::new(vec![
+        (CRC, packet(1, &data, false)),
+        (ACK, vec![CAN, CAN]),
+    ]);
+    let mut buf = [0; 512];
+    assert_eq!(peer.receive(&mut buf), Err(Error::Cancelled));
+}
+
+#[test]
+fn sender_cancels_before_start() {
+    let mut peer = Peer::new(vec![(CRC, vec![CAN, CAN])]);
+    let mut buf = [0; 128];
+    assert_eq!(peer.receive(&mut buf), Err(Error::Cancelled));
+}
+
+#[test]
+fn no_sender_times_out() {
+    let mut peer = Peer::new(Vec::new());
+    let mut buf = [0; 128];
+    assert_eq!(peer.receive(&mut buf), Err(Error::Timeout));
+    // 每秒请求一次，一共一分钟
+    assert_eq!(peer.count(CRC), 60);
+    assert_eq!(peer.output.len(), 60);
+}